@@ -0,0 +1,160 @@
+//! Benchmarks of the knapsack solver and market lookups, over synthetic markets so that no
+//! database is needed
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use kural::solve::{solve_knapsack, SolveParams, Solver};
+use kural::types::{Commodity, Station, StationMarket, TradeSolution};
+use rayon::prelude::*;
+use std::sync::Mutex;
+
+/// Number of commodities listed in each synthetic market
+const MARKET_SIZES: [usize; 4] = [10, 50, 100, 300];
+
+fn station(id: i64) -> Station {
+    Station {
+        id,
+        name: format!("Station {id}"),
+        distance_to_arrival: None,
+        market_id: Some(id),
+        system_id: Some(id),
+        system_name: Some(format!("System {id}")),
+        station_type: None,
+    }
+}
+
+/// Builds a market of the given number of commodities. Prices are spread deterministically, so
+/// the destination pays more than the source for some commodities and less for others.
+fn market(id: i64, size: usize, markup: i32) -> StationMarket {
+    let commodities = (0..size)
+        .map(|i| {
+            let price = 100 + (i as i32 * 37) % 5_000;
+            Commodity {
+                market_id: id,
+                name: format!("Commodity {i}"),
+                mean_price: price,
+                buy_price: price,
+                sell_price: price + (i as i32 * markup) % 800 - 200,
+                demand: 5_000,
+                demand_bracket: 3,
+                stock: 1_000 + (i as i32 * 13) % 4_000,
+                stock_bracket: 3,
+                ..Default::default()
+            }
+        })
+        .collect();
+    StationMarket::new(station(id), commodities)
+}
+
+fn params(solver: Solver) -> SolveParams<'static> {
+    SolveParams {
+        capacity: 720,
+        capital: 50_000_000,
+        min_unit_profit: 0,
+        min_supply: 0,
+        estimated_sell_prices: None,
+        min_profit: 0,
+        min_roi: None,
+        only_commodities: None,
+        solver,
+    }
+}
+
+fn bench_solve_knapsack(c: &mut Criterion) {
+    for (name, solver) in [("highs", Solver::Highs), ("greedy", Solver::Greedy)] {
+        let mut group = c.benchmark_group(format!("solve_knapsack/{name}"));
+        let params = params(solver);
+        for size in MARKET_SIZES {
+            let source = market(1, size, 1);
+            let destination = market(2, size, 7);
+            group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+                b.iter(|| solve_knapsack(black_box(&source), black_box(&destination), &params))
+            });
+        }
+        group.finish();
+    }
+}
+
+/// Compares looking a commodity up by name in the market's map against the linear scan over its
+/// commodities that the map replaced
+fn bench_get_commodity(c: &mut Criterion) {
+    for (name, linear) in [("map", false), ("linear_scan", true)] {
+        let mut group = c.benchmark_group(format!("get_commodity/{name}"));
+        for size in MARKET_SIZES {
+            let market = market(1, size, 1);
+            // the last listed commodity is the worst case for a scan
+            let name = format!("Commodity {}", size - 1);
+            group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+                if linear {
+                    b.iter(|| {
+                        market
+                            .commodities
+                            .iter()
+                            .find(|commodity| commodity.name == *black_box(&name))
+                            .cloned()
+                    })
+                } else {
+                    b.iter(|| market.get_commodity(black_box(&name)))
+                }
+            });
+        }
+        group.finish();
+    }
+}
+
+/// Number of stations on each side of the synthetic all-pairs solve
+const COLLECT_STATIONS: i64 = 40;
+
+/// Compares the ways that `do_solve` can gather the trades its rayon workers find: pushing each one
+/// into a shared Mutex<Vec>, or accumulating them per worker with fold and appending them with
+/// reduce. Uses the greedy solver, whose solves are short enough for lock contention to show.
+fn bench_collect_solutions(c: &mut Criterion) {
+    let markets: Vec<StationMarket> = (0..COLLECT_STATIONS)
+        .map(|id| market(id, 50, 1 + id as i32 % 9))
+        .collect();
+    let params = params(Solver::Greedy);
+    let solve = |source: &StationMarket, destination: &StationMarket| {
+        solve_knapsack(source, destination, &params).ok().flatten()
+    };
+
+    let mut group = c.benchmark_group("collect_solutions");
+    group.bench_function("mutex", |b| {
+        b.iter(|| {
+            let solutions: Mutex<Vec<TradeSolution>> = Mutex::new(Vec::new());
+            markets.par_iter().for_each(|source| {
+                for destination in &markets {
+                    if let Some(solution) = solve(source, destination) {
+                        solutions.lock().unwrap().push(solution);
+                    }
+                }
+            });
+            solutions.into_inner().unwrap()
+        })
+    });
+    group.bench_function("fold_reduce", |b| {
+        b.iter(|| {
+            markets
+                .par_iter()
+                .fold(Vec::new, |mut solutions, source| {
+                    solutions.extend(
+                        markets
+                            .iter()
+                            .filter_map(|destination| solve(source, destination)),
+                    );
+                    solutions
+                })
+                .reduce(Vec::new, |mut solutions, mut other| {
+                    solutions.append(&mut other);
+                    solutions
+                })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_solve_knapsack,
+    bench_get_commodity,
+    bench_collect_solutions
+);
+criterion_main!(benches);
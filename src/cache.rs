@@ -0,0 +1,314 @@
+use crate::types::{Commodity, TradeSolution};
+use crate::LandingPad;
+use chrono::NaiveDateTime;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use dashmap::DashMap;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Commodities fetched from Postgres, saved to disk so that repeated runs can skip fetching them
+#[derive(Debug, Serialize, Deserialize)]
+struct CommodityCache {
+    /// Listings older than this were excluded when the cache was built
+    date_cutoff: NaiveDateTime,
+    /// Landing pad that the cached stations were selected for
+    landing_pad: LandingPad,
+    /// Commodities at each station, keyed by station ID
+    commodities: HashMap<i64, Vec<Commodity>>,
+}
+
+/// Reads the commodity cache at the given path. The cache is rejected if it was built for a
+/// different landing pad, or if it is missing listings newer than `date_cutoff` (because it was
+/// built with a later cutoff). Cached listings older than `date_cutoff` are dropped.
+pub fn read_commodity_cache(
+    path: &Path,
+    date_cutoff: &NaiveDateTime,
+    landing_pad: LandingPad,
+) -> Result<HashMap<i64, Vec<Commodity>>> {
+    let cache: CommodityCache = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+    if cache.landing_pad != landing_pad {
+        return Err(eyre!(
+            "Commodity cache {} was built for a {:?} landing pad, not {:?}. Re-run without \
+            --use-cache to rebuild it.",
+            path.display(),
+            cache.landing_pad,
+            landing_pad
+        ));
+    }
+    if cache.date_cutoff > *date_cutoff {
+        return Err(eyre!(
+            "Commodity cache {} only has listings since {}, which is later than the cutoff of {}. \
+            Re-run without --use-cache to rebuild it.",
+            path.display(),
+            cache.date_cutoff,
+            date_cutoff
+        ));
+    }
+
+    Ok(cache
+        .commodities
+        .into_iter()
+        .map(|(id, commodities)| {
+            let fresh = commodities
+                .into_iter()
+                .filter(|commodity| commodity.listed_at >= *date_cutoff)
+                .collect();
+            (id, fresh)
+        })
+        .collect())
+}
+
+/// Writes the commodities to the cache at the given path, overwriting it
+pub fn write_commodity_cache(
+    path: &Path,
+    date_cutoff: &NaiveDateTime,
+    landing_pad: LandingPad,
+    commodities: &DashMap<i64, Vec<Commodity>>,
+) -> Result<()> {
+    let cache = CommodityCache {
+        date_cutoff: *date_cutoff,
+        landing_pad,
+        commodities: commodities
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect(),
+    };
+    serde_json::to_writer(BufWriter::new(File::create(path)?), &cache)?;
+    Ok(())
+}
+
+/// Progress of the solve phase of a run, saved periodically so that a run that crashed or was
+/// interrupted can be resumed with `--resume-from`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Seed of the run's random sample, which must be reused so that the same stations are sampled
+    pub seed: u64,
+    /// Parameters of the run, which a resumed run must match
+    pub run: CheckpointRun,
+    /// IDs of the source stations whose trades have all been solved
+    pub completed: HashSet<i64>,
+    /// Trades found from the completed source stations
+    pub solutions: Vec<TradeSolution>,
+}
+
+/// Parameters of the run that made a checkpoint. The trades in a checkpoint are only comparable to
+/// the trades of a run with the same parameters, so a checkpoint can't be resumed by any other run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointRun {
+    /// Capital that can be spent on each trade, after the reserve
+    pub capital: u64,
+    pub capacity: u32,
+    pub landing_pad: LandingPad,
+    pub expiry: Option<u32>,
+    pub src: Option<String>,
+    /// IDs of every sampled station, sorted
+    pub sampled: Vec<i64>,
+}
+
+impl CheckpointRun {
+    /// Checks that a checkpoint made by the `checkpointed` run can be resumed by this run, returning
+    /// an error listing everything that differs between them if not
+    pub fn check_resumes(&self, checkpointed: &CheckpointRun) -> Result<()> {
+        let mut differences = Vec::new();
+        if self.capital != checkpointed.capital {
+            differences.push(format!(
+                "a capital of {} CR, not {} CR",
+                checkpointed.capital, self.capital
+            ));
+        }
+        if self.capacity != checkpointed.capacity {
+            differences.push(format!(
+                "a capacity of {}t, not {}t",
+                checkpointed.capacity, self.capacity
+            ));
+        }
+        if self.landing_pad != checkpointed.landing_pad {
+            differences.push(format!(
+                "a {:?} landing pad, not {:?}",
+                checkpointed.landing_pad, self.landing_pad
+            ));
+        }
+        if self.expiry != checkpointed.expiry {
+            differences.push(format!(
+                "an expiry of {:?} days, not {:?}",
+                checkpointed.expiry, self.expiry
+            ));
+        }
+        if self.src != checkpointed.src {
+            differences.push(format!(
+                "a source of {:?}, not {:?}",
+                checkpointed.src, self.src
+            ));
+        }
+        // with the same seed, this only happens if the stations in the database have changed
+        if self.sampled != checkpointed.sampled {
+            differences.push("a different sample of stations".to_string());
+        }
+
+        if differences.is_empty() {
+            return Ok(());
+        }
+        Err(eyre!(
+            "The checkpoint was made with {}, so it can't be resumed by this run",
+            differences.join(", ")
+        ))
+    }
+}
+
+/// Reads the checkpoint at the given path
+pub fn read_checkpoint(path: &Path) -> Result<Checkpoint> {
+    let file = File::open(path)
+        .map_err(|err| eyre!("Could not open checkpoint {}: {err}", path.display()))?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+/// Writes the checkpoint to the given path. It's written to a temporary file first and then moved
+/// into place, so that a crash while writing never destroys the previous checkpoint.
+pub fn write_checkpoint(path: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let partial = path.with_extension("partial");
+    serde_json::to_writer(BufWriter::new(File::create(&partial)?), checkpoint)?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Collects the trades found from each source station as it completes during the solve, writing a
+/// checkpoint every `every` completed sources. The trades are held twice while solving, so this
+/// needs more memory than a run without checkpoints.
+pub struct Checkpointer {
+    path: PathBuf,
+    every: usize,
+    /// The checkpoint so far, and the number of sources completed since it was last written
+    state: Mutex<(Checkpoint, usize)>,
+    /// Held while a checkpoint is being written, so that writes never overlap or go out of order
+    writing: Mutex<()>,
+}
+
+impl Checkpointer {
+    /// Creates a checkpointer writing to the given path, starting from an existing checkpoint
+    pub fn new(path: PathBuf, every: usize, checkpoint: Checkpoint) -> Self {
+        Self {
+            path,
+            every,
+            state: Mutex::new((checkpoint, 0)),
+            writing: Mutex::new(()),
+        }
+    }
+
+    /// Records a source station as completed along with the trades found from it, writing a
+    /// checkpoint if enough sources have completed since the last one. The checkpoint is written
+    /// from a snapshot, so other workers can keep recording while it's saved.
+    pub fn complete(&self, source: i64, solutions: &[TradeSolution]) {
+        let (_writing, snapshot) = {
+            let mut state = self.state.lock().unwrap();
+            let (checkpoint, pending) = &mut *state;
+            checkpoint.completed.insert(source);
+            checkpoint.solutions.extend_from_slice(solutions);
+            *pending += 1;
+            if *pending < self.every {
+                return;
+            }
+            // if another worker is still writing, the sources stay pending and the next one to
+            // complete writes them instead
+            let Ok(writing) = self.writing.try_lock() else {
+                return;
+            };
+            *pending = 0;
+            (writing, checkpoint.clone())
+        };
+        // a failed checkpoint shouldn't abort the run it's meant to protect
+        if let Err(err) = write_checkpoint(&self.path, &snapshot) {
+            warn!("Could not write checkpoint {}: {err}", self.path.display());
+        }
+    }
+
+    /// Writes a checkpoint of everything completed so far
+    pub fn finish(&self) -> Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        let snapshot = self.state.lock().unwrap().0.clone();
+        write_checkpoint(&self.path, &snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn cutoff(day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2025, 1, day)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn rejects_mismatched_commodity_cache() {
+        let path = std::env::temp_dir().join(format!("kural-cache-{}.json", std::process::id()));
+        write_commodity_cache(&path, &cutoff(10), LandingPad::Large, &DashMap::new()).unwrap();
+
+        // a cache built for another pad, or with a later cutoff, may be missing stations or listings
+        let other_pad = read_commodity_cache(&path, &cutoff(10), LandingPad::Medium);
+        let earlier_cutoff = read_commodity_cache(&path, &cutoff(5), LandingPad::Large);
+        let matching = read_commodity_cache(&path, &cutoff(10), LandingPad::Large);
+        let later_cutoff = read_commodity_cache(&path, &cutoff(20), LandingPad::Large);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(other_pad.is_err());
+        assert!(earlier_cutoff.is_err());
+        assert!(matching.is_ok());
+        assert!(later_cutoff.is_ok());
+    }
+
+    fn run() -> CheckpointRun {
+        CheckpointRun {
+            capital: 1_000_000,
+            capacity: 100,
+            landing_pad: LandingPad::Large,
+            expiry: Some(7),
+            src: Some("Sol".to_string()),
+            sampled: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_checkpoint() {
+        assert!(run().check_resumes(&run()).is_ok());
+
+        let mismatched = [
+            CheckpointRun {
+                capital: 2_000_000,
+                ..run()
+            },
+            CheckpointRun {
+                capacity: 200,
+                ..run()
+            },
+            CheckpointRun {
+                landing_pad: LandingPad::Medium,
+                ..run()
+            },
+            CheckpointRun {
+                expiry: None,
+                ..run()
+            },
+            CheckpointRun { src: None, ..run() },
+            CheckpointRun {
+                sampled: vec![1, 2, 4],
+                ..run()
+            },
+        ];
+        for checkpointed in mismatched {
+            assert!(
+                run().check_resumes(&checkpointed).is_err(),
+                "{checkpointed:?} should not be resumable"
+            );
+        }
+    }
+}
@@ -1,56 +1,162 @@
-use crate::solve::solve_knapsack;
-use crate::types::{get_system_by_name, Coordinate};
-use crate::types::{Commodity, Station, StationMarket, System, TradeSolution};
-use crate::LandingPad;
+use crate::cache::{read_checkpoint, read_commodity_cache, write_commodity_cache};
+use crate::cache::{Checkpoint, CheckpointRun, Checkpointer};
+use crate::eddn;
+use crate::error::KuralError;
+use crate::export::{write_csv, write_json, write_listings_csv, MatrixExporter};
+use crate::solve::{
+    cheapest_profitable_price, explain_knapsack, single_commodity_profit, solve_knapsack,
+    SolveParams,
+};
+use crate::tui::{browse, BrowsedTrade};
+use crate::types::{get_stations_by_name, get_system_by_name, set_number_format};
+use crate::types::{
+    Baseline, Commodity, Coordinate, Station, StationMarket, System, TradeSolution,
+};
+use crate::types::{CheapestListing, StationRow};
+use crate::types::{SystemCache, SystemRow};
+use crate::{
+    Carriers, ComputeMultiArgs, ComputeSingleArgs, FindBestSellArgs, FindCheapestArgs,
+    FindLoopsArgs, LandingPad, OutputFormat, SampleStrategy, SolvePairArgs, SortBy, SortDirection,
+};
+use anstream::{eprintln, println, AutoStream};
 use chrono::{NaiveDate, NaiveDateTime, TimeDelta};
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use dashmap::DashMap;
 use futures::StreamExt;
-use geozero::wkb;
-use indicatif::ProgressBar;
+use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use lazy_static::lazy_static;
+use log::{error, warn};
 use ordered_float::OrderedFloat;
 use owo_colors::colors::css::{DarkOrange, Orange};
 use owo_colors::colors::*;
 use owo_colors::OwoColorize;
-use rand::{rngs::SmallRng, seq::IteratorRandom, SeedableRng};
+use rand::{rngs::SmallRng, seq::IteratorRandom, seq::SliceRandom, SeedableRng};
+use rayon::iter::IntoParallelIterator;
 use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use regex::Regex;
+use serde::Serialize;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::types::chrono::Utc;
 use sqlx::{Pool, Postgres};
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
 use std::process::exit;
-use std::sync::{Arc, Mutex};
-
-#[allow(unused_variables)]
-
-/// Gets a list of all stations
-async fn get_all_stations(pool: &Pool<Postgres>, landing_pad: LandingPad) -> Result<Vec<Station>> {
-    let pad_name = if landing_pad == LandingPad::Small {
-        "%s%"
-    } else if landing_pad == LandingPad::Medium {
-        "%m%"
-    } else if landing_pad == LandingPad::Large {
-        "%l%"
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+use thousands::Separable;
+use tokio::signal;
+
+/// Set when results are written to stdout in a machine-readable format, in which case status lines
+/// and progress bars are suppressed so that stdout stays parseable
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Prints a status line to stdout, unless status output is being suppressed
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Style shared by all progress bars, showing elapsed time, progress, throughput and ETA
+fn progress_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "[{elapsed_precise}] {wide_bar} {human_pos}/{human_len} ({per_sec}, ETA {eta})",
+    )
+    .expect("progress bar template is valid")
+}
+
+/// Creates a progress bar of the given length, which is hidden if status output is being suppressed
+fn progress_bar(len: usize) -> ProgressBar {
+    if QUIET.load(Ordering::Relaxed) {
+        ProgressBar::hidden()
     } else {
-        panic!();
+        ProgressBar::new(len.try_into().unwrap()).with_style(progress_style())
+    }
+}
+
+/// Patterns matching the `landing_pad` column of stations that a ship needing the given landing pad
+/// can dock at. Ships can always use a pad larger than they need, so small ships can dock at any
+/// station, while large ships need a large pad.
+fn landing_pad_patterns(landing_pad: LandingPad) -> Vec<String> {
+    let sizes: &[&str] = match landing_pad {
+        LandingPad::Small => &["s", "m", "l"],
+        LandingPad::Medium => &["m", "l"],
+        LandingPad::Large => &["l"],
     };
+    sizes.iter().map(|size| format!("%{size}%")).collect()
+}
 
-    return Ok(sqlx::query_as!(
-        Station,
+/// Listings older than this are ignored: `expiry` days ago, or the beginning of time if not set
+fn date_cutoff(expiry: Option<u32>) -> NaiveDateTime {
+    match expiry {
+        Some(days) => (Utc::now() - TimeDelta::days(days.into())).naive_utc(),
+        None => NaiveDate::from_ymd_opt(1970, 1, 1).unwrap().into(),
+    }
+}
+
+/// Gets a list of all stations, alongside a map of station ids to the system each one is in.
+/// Station names aren't unique across the galaxy, so they can't be used as the key.
+async fn get_all_stations(
+    pool: &Pool<Postgres>,
+    landing_pad: LandingPad,
+) -> Result<(Vec<Station>, HashMap<i64, Arc<System>>)> {
+    let pad_names = landing_pad_patterns(landing_pad);
+
+    let rows = sqlx::query_as!(
+        StationRow,
         r#"
-            SELECT s.id, s.name AS name, s.distance_to_arrival, s.market_id, s.system_id, y.name AS system_name
+            SELECT s.id, s.name AS name, s.distance_to_arrival, s.market_id, s.system_id, y.name AS system_name,
+                   s.station_type, y.date AS system_date, ST_AsEWKB(y.coords) AS system_coords,
+                   y.needs_permit AS system_needs_permit
                 FROM stations s
             INNER JOIN systems y ON y.id = s.system_id
-                WHERE s.market_id IS NOT NULL AND s.system_id IS NOT NULL AND s.landing_pad LIKE $1;
+                WHERE s.market_id IS NOT NULL AND s.system_id IS NOT NULL AND s.landing_pad LIKE ANY($1)
+            ORDER BY s.id;
         "#,
-        pad_name
+        &pad_names
     )
     .fetch_all(pool)
-    .await?);
+    .await?;
+
+    // many stations share a system, so only decode (and warn about) each system once
+    let mut systems: HashMap<i64, Option<Arc<System>>> = HashMap::new();
+    let mut stations_systems: HashMap<i64, Arc<System>> = HashMap::new();
+    let mut stations = Vec::with_capacity(rows.len());
+    for row in rows {
+        let (station, system_row) = row.split();
+        if let Some(system_row) = system_row {
+            let system = systems
+                .entry(system_row.id)
+                .or_insert_with(|| system_row.decode().map(Arc::new));
+            if let Some(system) = system {
+                stations_systems.insert(station.id, system.clone());
+            }
+        }
+        stations.push(station);
+    }
+
+    Ok((stations, stations_systems))
+}
+
+/// Maps each station's id to the coordinates of its system, so that the distance checks between
+/// every pair of stations in the solve loops are a single lookup
+fn station_coordinates(
+    stations_systems_map: &HashMap<i64, Arc<System>>,
+) -> HashMap<i64, Coordinate> {
+    stations_systems_map
+        .iter()
+        .map(|(id, system)| (*id, system.coords))
+        .collect()
 }
 
 /// Gets a list of all systems in range of the given system
@@ -59,12 +165,12 @@ async fn get_all_systems_in_range(
     source: &System,
     range: f64,
 ) -> Result<Vec<System>> {
-    let coord = source.coords.geometry.expect("no coordinate");
+    let coord = source.coords;
 
     return Ok(sqlx::query_as!(
-        System,
+        SystemRow,
         r#"
-            SELECT id, name, date, coords AS "coords!: wkb::Decode<Coordinate>"
+            SELECT id, name, date, ST_AsEWKB(coords) AS coords, needs_permit
                 FROM systems
             WHERE ST_3DDWithin(coords, ST_MakePoint($1, $2, $3), $4)
         "#,
@@ -74,342 +180,3646 @@ async fn get_all_systems_in_range(
         range,
     )
     .fetch_all(pool)
+    .await?
+    .into_iter()
+    .filter_map(SystemRow::decode)
+    .collect());
+}
+
+/// Counts all stations in the given systems, regardless of landing pad or market
+async fn count_stations_in_systems(pool: &Pool<Postgres>, system_ids: &[i64]) -> Result<i64> {
+    return Ok(sqlx::query_scalar!(
+        r#"
+            SELECT COUNT(*) AS "count!"
+                FROM stations
+            WHERE system_id = ANY($1);
+        "#,
+        system_ids,
+    )
+    .fetch_one(pool)
     .await?);
 }
 
+/// Computes the galactic average sell price of each commodity, considering only stations that are
+/// actually buying it. The result is a map of commodity names to their average sell price.
+async fn get_average_sell_prices(
+    pool: &Pool<Postgres>,
+    date_cutoff: &NaiveDateTime,
+) -> Result<HashMap<String, i32>> {
+    return Ok(sqlx::query!(
+        r#"
+            SELECT name, ROUND(AVG(sell_price))::int4 AS "average!"
+                FROM listings
+            WHERE sell_price > 0 AND demand > 0 AND listed_at >= $1
+                GROUP BY name;
+        "#,
+        date_cutoff,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.name, row.average))
+    .collect());
+}
+
+/// Number of days after which a market's weight halves when sampling with `--sample-strategy
+/// by-freshness`
+const FRESHNESS_HALF_LIFE_DAYS: f64 = 7.0;
+
+/// Weighs each market for a non-uniform random sample, keyed by market ID. Markets without any
+/// listings newer than `date_cutoff` are left out, so they're only sampled once every other market
+/// has been.
+async fn get_market_weights(
+    pool: &Pool<Postgres>,
+    strategy: SampleStrategy,
+    date_cutoff: &NaiveDateTime,
+) -> Result<HashMap<i64, f64>> {
+    let now = Utc::now().naive_utc();
+    let rows = sqlx::query!(
+        r#"
+            SELECT market_id AS "market_id!", MAX(listed_at) AS "newest_listing!",
+                   SUM(stock)::int8 AS "total_stock!"
+                FROM (
+                    SELECT DISTINCT ON (market_id, name) market_id, stock, listed_at
+                        FROM listings
+                    WHERE listed_at >= $1
+                        ORDER BY market_id, name, listed_at DESC
+                ) latest
+            GROUP BY market_id;
+        "#,
+        date_cutoff,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let weight = match strategy {
+                SampleStrategy::Uniform => 1.0,
+                SampleStrategy::ByFreshness => {
+                    let age_days =
+                        (now - row.newest_listing).num_seconds().max(0) as f64 / 86_400.0;
+                    0.5f64.powf(age_days / FRESHNESS_HALF_LIFE_DAYS)
+                }
+                SampleStrategy::ByStock => row.total_stock.max(0) as f64,
+            };
+            (row.market_id, weight)
+        })
+        .collect())
+}
+
+/// How commodities are fetched from Postgres
+#[derive(Debug, Clone, Copy)]
+struct FetchOptions {
+    /// Maximum number of stations to fetch at once
+    concurrency: usize,
+    /// Number of times a failed fetch is retried before the station is skipped
+    retries: u32,
+}
+
+/// Delay before the first retry of a failed fetch, which doubles with every further retry
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest delay between two retries of a failed fetch, however many retries were asked for
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Gets the commodities in a station, retrying with exponential backoff if the query fails with a
+/// transient error, since long runs occasionally lose their connection to Postgres
+async fn get_commodities_with_retry(
+    station: &Station,
+    pool: &Pool<Postgres>,
+    date_cutoff: &NaiveDateTime,
+    retries: u32,
+) -> Result<Vec<Commodity>, KuralError> {
+    let mut attempt = 0;
+    loop {
+        match station.get_commodities(pool, date_cutoff).await {
+            Ok(commodities) => return Ok(commodities),
+            Err(err) if attempt < retries && err.is_transient() => {
+                let delay = RETRY_BASE_DELAY
+                    .saturating_mul(2u32.saturating_pow(attempt))
+                    .min(RETRY_MAX_DELAY);
+                warn!(
+                    "Fetching {} failed, retrying in {:?}: {}",
+                    station.name, delay, err
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
 /// Finds commodities for a group of stations. The result is a map of IDs to the commodities at
 /// that station.
 async fn get_all_commodities(
     stations: &[Station],
     pool: &Pool<Postgres>,
     date_cutoff: &NaiveDateTime,
+    options: FetchOptions,
 ) -> Result<Arc<DashMap<i64, Vec<Commodity>>>> {
     let out: Arc<DashMap<i64, Vec<Commodity>>> = Arc::new(DashMap::new());
 
-    let bar = Arc::new(ProgressBar::new(stations.len().try_into().unwrap()));
+    let bar = Arc::new(progress_bar(stations.len()));
     futures::stream::iter(stations.iter())
-        .for_each(|station1| {
+        .for_each_concurrent(options.concurrency, |station1| {
             let pool = pool.clone();
             let bar = bar.clone();
             let out = out.clone();
             async move {
+                // a station that can't be fetched is left out, rather than failing the whole run
+                match get_commodities_with_retry(station1, &pool, date_cutoff, options.retries)
+                    .await
+                {
+                    Ok(commodities) => {
+                        out.insert(station1.id, commodities);
+                    }
+                    Err(err) => error!("Skipping station {}: {}", station1.name, err),
+                }
+                // only count the station once its fetch has actually finished
                 bar.inc(1);
-                let commodities = station1.get_commodities(&pool, date_cutoff).await.unwrap();
-                out.insert(station1.id, commodities);
             }
         })
         .await;
+    bar.finish();
 
     Ok(out)
 }
 
-lazy_static! {
-    static ref FLEET_CARRIER_REGEX: Regex = Regex::new("[a-zA-Z0-9]{3}-[a-zA-Z0-9]{3}").unwrap();
+/// Finds commodities for a group of stations like `get_all_commodities`, but reads them from the
+/// commodity cache (if `use_cache` is set) and writes them back to it (if a cache path is given).
+/// Only the stations missing from the cache are fetched from Postgres.
+async fn get_all_commodities_cached(
+    stations: &[Station],
+    pool: &Pool<Postgres>,
+    date_cutoff: &NaiveDateTime,
+    options: FetchOptions,
+    landing_pad: LandingPad,
+    cache: Option<&Path>,
+    use_cache: bool,
+) -> Result<Arc<DashMap<i64, Vec<Commodity>>>> {
+    let cached = match cache {
+        Some(path) if use_cache => {
+            let cached = read_commodity_cache(path, date_cutoff, landing_pad)?;
+            status!(
+                "Loaded cached commodities for {} stations from {}",
+                cached.len().fg::<Orange>(),
+                path.display().fg::<Orange>()
+            );
+            cached
+        }
+        _ => HashMap::new(),
+    };
+
+    let missing: Vec<Station> = stations
+        .iter()
+        .filter(|station| !cached.contains_key(&station.id))
+        .cloned()
+        .collect();
+    if missing.is_empty() {
+        return Ok(Arc::new(cached.into_iter().collect()));
+    }
+    if use_cache {
+        status!(
+            "...fetching {} stations missing from the cache",
+            missing.len().fg::<Orange>()
+        );
+    }
+
+    let out = get_all_commodities(&missing, pool, date_cutoff, options).await?;
+    for (id, commodities) in cached {
+        out.insert(id, commodities);
+    }
+    if let Some(path) = cache {
+        write_commodity_cache(path, date_cutoff, landing_pad, &out)?;
+        status!("Cached commodities to {}", path.display().fg::<Orange>());
+    }
+    Ok(out)
 }
 
-/// Returns true if the station name is a fleet carrier
-fn is_fleet_carrier(name: &str) -> bool {
-    FLEET_CARRIER_REGEX.find(name).is_some()
+/// Commodity match statistics accumulated across all evaluated station pairs, used to give users
+/// an idea of how sparse the underlying market data is
+#[derive(Debug, Default)]
+pub struct MatchStats {
+    /// Number of station pairs evaluated
+    pairs: AtomicUsize,
+    /// Sum of overlapping commodities across all evaluated pairs
+    overlapping: AtomicUsize,
+    /// Number of pairs which had no commodities in common (and hence no possible trade)
+    zero_overlap: AtomicUsize,
+    /// Number of pairs with at least one commodity that could be traded at a profit
+    profitable: AtomicUsize,
+    /// Number of profitable pairs where every profitable commodity costs more than the capital
+    unaffordable: AtomicUsize,
+    /// Number of pairs skipped without being evaluated, since the source sells nothing that the
+    /// destination buys
+    pruned: AtomicUsize,
+    /// Number of knapsack solves attempted
+    solves: AtomicUsize,
+    /// Number of solves that found a trade
+    solved: AtomicUsize,
+    /// Number of solves that found nothing in common that could be traded at a profit
+    no_trade: AtomicUsize,
+    /// Number of solves where the solver itself failed, e.g. because the problem was infeasible
+    failed: AtomicUsize,
+    /// Total time spent solving, in microseconds
+    solve_micros: AtomicU64,
 }
 
-/// Computes a single hop route
-pub async fn compute_single(
-    url: String,
-    src: Option<String>,
-    src_search_ly: Option<f32>,
-    capital: u64,
-    capacity: u32,
-    sample_factor: f32,
-    landing_pad: LandingPad,
-    expiry: Option<u32>,
-    max_dst: Option<f32>,
-) -> Result<()> {
-    println!("Setting up PostgreSQL pool on {}", url.fg::<Orange>());
-    let var_name = PgPoolOptions::new();
-    let pool = var_name.max_connections(32).connect(&url).await?;
+impl MatchStats {
+    /// Records a pair of stations which had `overlap` commodities in common
+    pub fn record(&self, overlap: usize) {
+        self.pairs.fetch_add(1, Ordering::Relaxed);
+        self.overlapping.fetch_add(overlap, Ordering::Relaxed);
+        if overlap == 0 {
+            self.zero_overlap.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-    // compute date cutoff: if expiry is set, use now - expiry; otherwise use 1970-01-01
-    let date_cutoff = match expiry {
-        Some(exp) => (Utc::now() - TimeDelta::days(exp.into())).naive_utc(),
-        None => NaiveDate::from_ymd_opt(1970, 1, 1).unwrap().into(),
-    };
+    /// Records the buy price of the cheapest commodity that could be traded at a profit for a pair,
+    /// if any, to help explain why no trades were found
+    pub fn record_cheapest_profitable(&self, price: Option<i32>, capital: u64) {
+        if let Some(price) = price {
+            self.profitable.fetch_add(1, Ordering::Relaxed);
+            if price as u64 > capital {
+                self.unaffordable.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
 
-    println!("Fetching all stations");
-    let stations = get_all_stations(&pool, landing_pad).await?;
+    /// Records a pair of stations that was skipped because they have nothing to trade
+    pub fn record_pruned(&self) {
+        self.pruned.fetch_add(1, Ordering::Relaxed);
+    }
 
-    // the galaxy is very large, so randomly sample a number of stations
-    // FIXME handle cases where the number of stations is very small and we end up with a size of 0
-    let sample_size: usize = (sample_factor * (stations.len() as f32)).round() as usize;
-    println!(
-        "Computing random sample, factor: {} ({} stations)",
-        sample_factor.fg::<Orange>(),
-        sample_size.fg::<Orange>()
-    );
-    // use SmallRng for speed
-    let mut rng = SmallRng::from_entropy();
-    // ensure that we are only selecting stations that have a market and system attached to
-    // them
-    let valid_stations: Vec<Station> = stations
-        .iter()
-        .filter(|station| {
-            station.market_id.is_some()
-                && station.system_id.is_some()
-                && !is_fleet_carrier(&station.name)
-        })
-        .cloned()
-        .collect();
+    /// Records the outcome of a single knapsack solve, and how long it took
+    pub fn record_solve(
+        &self,
+        result: &Result<Option<TradeSolution>, KuralError>,
+        elapsed: Duration,
+    ) {
+        self.solves.fetch_add(1, Ordering::Relaxed);
+        self.solve_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        let outcome = match result {
+            Ok(Some(_)) => &self.solved,
+            Ok(None) => &self.no_trade,
+            Err(_) => &self.failed,
+        };
+        outcome.fetch_add(1, Ordering::Relaxed);
+    }
 
-    // now we can compute the random subsample
-    let mut random_sample: Vec<Station> = valid_stations
-        .iter()
-        .choose_multiple(&mut rng, sample_size)
-        .iter()
-        .map(|it| (*it).clone())
-        .collect();
+    /// Prints the outcomes of the knapsack solves to stderr
+    pub fn print_solver_stats(&self) {
+        let solves = self.solves.load(Ordering::Relaxed);
+        let average_ms = if solves == 0 {
+            0.0
+        } else {
+            self.solve_micros.load(Ordering::Relaxed) as f64 / solves as f64 / 1000.0
+        };
+        eprintln!("{}", "🧮 Solver stats:".bold().fg::<Green>());
+        eprintln!(
+            "    {} solves attempted, taking {} on average",
+            solves.separate_with_commas().fg::<Orange>(),
+            format!("{average_ms:.3} ms").fg::<Orange>()
+        );
+        eprintln!(
+            "    {} found a trade",
+            self.solved
+                .load(Ordering::Relaxed)
+                .separate_with_commas()
+                .fg::<Green>()
+        );
+        eprintln!(
+            "    {} had nothing in common to trade at a profit",
+            self.no_trade
+                .load(Ordering::Relaxed)
+                .separate_with_commas()
+                .fg::<Orange>()
+        );
+        eprintln!(
+            "    {} failed in the solver, e.g. as infeasible",
+            self.failed
+                .load(Ordering::Relaxed)
+                .separate_with_commas()
+                .fg::<Red>()
+        );
+    }
 
-    let all_solutions: Mutex<Vec<TradeSolution>> = Mutex::new(Vec::new());
+    /// Number of station pairs skipped because they have nothing to trade
+    pub fn pruned(&self) -> usize {
+        self.pruned.load(Ordering::Relaxed)
+    }
 
-    match src {
-        Some(ref source) => {
-            let stations_filtered: Vec<Station> = if let Some(dst) = src_search_ly {
-                // not a fixed source set, search within 'dst' LY of the source system
-                let source_system =
-                    get_system_by_name(&pool, src.as_ref().expect("src must be specified")).await?;
+    /// Number of station pairs evaluated
+    pub fn pairs(&self) -> usize {
+        self.pairs.load(Ordering::Relaxed)
+    }
 
-                println!(
-                    "Finding acceptable systems in {} LY range of {}",
-                    dst.fg::<Orange>(),
-                    source.fg::<Orange>()
-                );
-                let systems: HashSet<String> =
-                    get_all_systems_in_range(&pool, &source_system, dst.into())
-                        .await?
-                        .iter()
-                        .map(|x| x.name.clone())
-                        .collect();
-                println!(
-                    "...found {} acceptable systems",
-                    systems.len().fg::<Orange>()
-                );
+    /// Average number of overlapping commodities per evaluated pair
+    pub fn average_overlap(&self) -> f64 {
+        let pairs = self.pairs.load(Ordering::Relaxed);
+        if pairs == 0 {
+            return 0.0;
+        }
+        self.overlapping.load(Ordering::Relaxed) as f64 / pairs as f64
+    }
 
-                println!("Now filtering stations");
-                stations
-                    .iter()
-                    .filter(|x| {
-                        !is_fleet_carrier(&x.name)
-                            && x.system_name
-                                .clone()
-                                .is_some_and(|it| systems.contains(&it))
-                    })
-                    .map(|x| (*x).clone())
-                    .collect()
-                // TODO randomly subsample stations_filtered further? if it's a large number?
-            } else {
-                // fixed source set, pinned to a particular system
-                println!("Filtering all stations to fixed starting system '{source}'");
-                stations
-                    .iter()
-                    .filter(|x| {
-                        x.system_name
-                            .as_ref()
-                            .is_some_and(|s| s.to_lowercase() == source.to_lowercase())
-                    })
-                    .map(|x| (*x).clone())
-                    .collect()
-            };
+    /// Fraction between 0.0 and 1.0 of evaluated pairs that had no commodities in common
+    pub fn zero_overlap_fraction(&self) -> f64 {
+        let pairs = self.pairs.load(Ordering::Relaxed);
+        if pairs == 0 {
+            return 0.0;
+        }
+        self.zero_overlap.load(Ordering::Relaxed) as f64 / pairs as f64
+    }
+}
 
-            // extend the random sample with our fixed subsample (for when we do market lookup)
-            random_sample.extend(stations_filtered.clone().into_iter());
+/// Prints what is most likely to blame when no trades at all were found
+fn explain_no_solutions(
+    match_stats: &MatchStats,
+    sampled: usize,
+    capital: u64,
+    min_unit_profit: u32,
+) {
+    let pairs = match_stats.pairs();
+    let overlapping = pairs - match_stats.zero_overlap.load(Ordering::Relaxed);
+    let profitable = match_stats.profitable.load(Ordering::Relaxed);
+    let unaffordable = match_stats.unaffordable.load(Ordering::Relaxed);
 
-            println!(
-                "Retrieving all commodities for {} sampled stations",
-                random_sample.len().fg::<Orange>()
-            );
-            let all_commodities = get_all_commodities(&random_sample, &pool, &date_cutoff).await?;
+    status!("{}", "⚠️ No trades were found.".bold().fg::<DarkOrange>());
+    status!(
+        "    {} stations were sampled, and {} station pairs were evaluated.",
+        sampled.fg::<Orange>(),
+        pairs.separate_with_commas().fg::<Orange>()
+    );
+    if pairs == 0 && match_stats.pruned() > 0 {
+        status!(
+            "    {} pairs were skipped since the source sells nothing the destination buys. Try a \
+            longer --expiry.",
+            match_stats.pruned().separate_with_commas().fg::<Orange>()
+        );
+        return;
+    }
+    if pairs == 0 {
+        status!("    Try a larger --random-sample, or widen --max-dst or --src-search-ly.");
+        return;
+    }
 
-            if all_commodities.is_empty() {
-                eprintln!("No commodities could be found after applying filtering. Maybe adjust your date cutoff?");
-                exit(1);
-            }
+    status!(
+        "    {} pairs had commodities in common, of which {} had a commodity that sells for more \
+        than it costs.",
+        overlapping.separate_with_commas().fg::<Orange>(),
+        profitable.separate_with_commas().fg::<Orange>()
+    );
+    if overlapping == 0 {
+        status!("    The sampled markets have nothing in common. Try a longer --expiry.");
+    } else if profitable == 0 {
+        status!("    No commodity can be sold for a profit between the sampled stations.");
+    } else if unaffordable == profitable {
+        status!(
+            "    Every profitable commodity costs more than your capital of {} CR. Try raising \
+            --capital.",
+            capital.separate_with_commas().fg::<Orange>()
+        );
+    } else if min_unit_profit > 0 {
+        status!("    Try lowering --min-unit-profit below {min_unit_profit}.");
+    }
+}
+
+/// Set by the first Ctrl-C while solving, after which no new knapsack solves are started and the
+/// best trades found so far are printed
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Set while trades are being solved, which is the only time that Ctrl-C doesn't quit immediately
+static SOLVING: AtomicBool = AtomicBool::new(false);
 
-            // nasty ass hack that we'll do to associate station names with system instances, since
-            // we can't async inside the stations_filtered.par_iter()
-            println!("Associating station names with system instances");
-            let mut stations_systems_map: HashMap<String, System> = HashMap::new();
-            let hash_bar = ProgressBar::new(random_sample.len().try_into().unwrap());
-            for station in &random_sample {
-                if let Some(system_name) = &station.system_name {
-                    stations_systems_map.insert(
-                        station.name.clone(),
-                        get_system_by_name(&pool, system_name).await?,
+/// Listens for Ctrl-C in the background, once per process. The first one while solving stops the
+/// solve early, so that the trades found so far can still be printed. Any other one quits
+/// immediately, like it would have without a listener.
+fn handle_interrupts() {
+    static LISTENING: Once = Once::new();
+    LISTENING.call_once(|| {
+        tokio::spawn(async {
+            while signal::ctrl_c().await.is_ok() {
+                if SOLVING.load(Ordering::Relaxed) && !INTERRUPTED.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "Interrupted, finishing up with the trades found so far. Press Ctrl-C \
+                        again to quit."
                     );
+                } else {
+                    exit(130);
                 }
-                hash_bar.inc(1);
             }
-            hash_bar.finish();
+        });
+    });
+}
 
-            println!(
-                "Computing trades for approx {} stations ({} '{source}'{})",
-                stations_filtered.len().fg::<Orange>(),
-                "with fixed start location".fg::<DarkOrange>(),
-                if let Some(dst) = src_search_ly {
-                    format!(" and within {dst} LY")
-                        .fg::<DarkOrange>()
-                        .to_string()
-                } else {
-                    "".to_string()
-                }
-            );
+/// Marks trades as being solved until dropped, so that Ctrl-C stops the solve early rather than
+/// quitting
+struct Solving;
 
-            do_solve(
-                &stations_filtered,
-                &random_sample,
-                &all_commodities,
-                &stations_systems_map,
-                capital,
-                capacity,
-                max_dst,
-                &all_solutions,
-            );
+impl Solving {
+    fn start() -> Self {
+        handle_interrupts();
+        SOLVING.store(true, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for Solving {
+    fn drop(&mut self) {
+        SOLVING.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Point in time after which no new knapsack solves are started, set with `--deadline`
+#[derive(Debug)]
+struct Deadline {
+    at: Option<Instant>,
+    /// Set once work has been skipped because the deadline passed
+    hit: AtomicBool,
+}
+
+impl Deadline {
+    /// Creates a deadline the given number of seconds from now, or no deadline at all
+    fn new(seconds: Option<u64>) -> Self {
+        Self {
+            at: seconds.map(|seconds| Instant::now() + Duration::from_secs(seconds)),
+            hit: AtomicBool::new(false),
         }
+    }
 
-        None => {
-            // no fixed source set
-            // here we compare every station with every other station in the list
-            println!(
-                "Retrieving all commodities for {} sampled stations",
-                random_sample.len().fg::<Orange>()
-            );
-            let all_commodities = get_all_commodities(&random_sample, &pool, &date_cutoff).await?;
-            if all_commodities.is_empty() {
-                eprintln!("No commodities could be found after applying filtering. Maybe adjust your date cutoff?");
-                exit(1);
+    /// Returns true if the deadline has passed or the user interrupted the solve, remembering that
+    /// it was hit
+    fn passed(&self) -> bool {
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            self.hit.store(true, Ordering::Relaxed);
+            return true;
+        }
+        match self.at {
+            Some(at) if Instant::now() >= at => {
+                self.hit.store(true, Ordering::Relaxed);
+                true
             }
+            _ => false,
+        }
+    }
 
-            // nasty ass hack that we'll do to associate station names with system instances, since
-            // we can't async inside the stations_filtered.par_iter()
-            println!("Associating station names with system instances");
-            let mut stations_systems_map: HashMap<String, System> = HashMap::new();
-            let hash_bar = ProgressBar::new(random_sample.len().try_into().unwrap());
-            for station in &random_sample {
-                if let Some(system_name) = &station.system_name {
-                    stations_systems_map.insert(
-                        station.name.clone(),
-                        get_system_by_name(&pool, system_name).await?,
-                    );
-                }
-                hash_bar.inc(1);
-            }
-            hash_bar.finish();
+    /// Returns true if any work was skipped because the deadline passed
+    fn was_hit(&self) -> bool {
+        self.hit.load(Ordering::Relaxed)
+    }
 
+    /// Explains why work was skipped, for the notes printed alongside partial results
+    fn reason(&self) -> &'static str {
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            "The solve was interrupted"
+        } else {
+            "The deadline was hit"
+        }
+    }
+
+    /// Starts a follow-up pass after the main solve, which shares this deadline but tracks whether
+    /// it was hit on its own. Returns None, after printing why, if the pass has no time left at all.
+    fn follow_up(&self, pass: &str) -> Option<Deadline> {
+        let deadline = Self {
+            at: self.at,
+            hit: AtomicBool::new(false),
+        };
+        if deadline.passed() {
             println!(
-                "Computing trades for {} stations (approx {} individual routes)",
-                random_sample.len().fg::<Orange>(),
-                // this is because its stations^2 minus self intersecting routes (like going from
-                // A->A)
-                (random_sample.len().pow(2) - random_sample.len()).fg::<Green>()
+                "{}",
+                format!("⏱️ {}, so {pass} was skipped", deadline.reason()).fg::<DarkOrange>()
             );
+            return None;
+        }
+        Some(deadline)
+    }
 
-            do_solve(
-                &random_sample,
-                &random_sample,
-                &all_commodities,
-                &stations_systems_map,
-                capital,
-                capacity,
-                max_dst,
-                &all_solutions,
+    /// Notes that a follow-up pass was cut short, so its results aren't mistaken for complete ones
+    fn note_cut_short(&self, pass: &str) {
+        if self.was_hit() {
+            println!(
+                "{}",
+                format!(
+                    "⏱️ {}, so {pass} only covers the pairs solved so far",
+                    self.reason()
+                )
+                .fg::<DarkOrange>()
             );
         }
     }
+}
 
-    let solutions = all_solutions.lock().unwrap();
-    let best_solutions: Vec<&TradeSolution> = solutions
-        .iter()
-        .sorted_by_key(|x| OrderedFloat(x.profit))
-        .rev()
-        .collect();
+/// Systems and stations that the user never wants to trade to or from
+#[derive(Debug, Default)]
+struct Exclusions {
+    /// Lowercased system names
+    systems: HashSet<String>,
+    /// Lowercased station names
+    stations: HashSet<String>,
+    /// Lowercased names read from the exclusion file, which may be either a system or a station
+    either: HashSet<String>,
+}
 
-    println!("{}", "✨ Most optimal trades:".bold().fg::<Green>());
-    for (i, trade) in best_solutions.iter().take(5).enumerate() {
-        println!("{}. {}", i + 1, trade.dump_coloured(&pool).await);
-        println!();
+impl Exclusions {
+    /// Builds the exclusions from the command line, reading further names from `file` if given
+    fn new(systems: &[String], stations: &[String], file: Option<&Path>) -> Result<Self> {
+        let either = match file {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|err| eyre!("Could not read exclusion file {}: {err}", path.display()))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_lowercase)
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(Self {
+            systems: systems.iter().map(|name| name.to_lowercase()).collect(),
+            stations: stations.iter().map(|name| name.to_lowercase()).collect(),
+            either,
+        })
     }
 
-    Ok(())
-}
+    fn is_empty(&self) -> bool {
+        self.systems.is_empty() && self.stations.is_empty() && self.either.is_empty()
+    }
 
-/// Break out of compute_single that actually computes the solution
-fn do_solve(
-    query: &[Station],
-    sample: &[Station],
-    all_commodities: &Arc<DashMap<i64, Vec<Commodity>>>,
-    stations_systems_map: &HashMap<String, System>,
-    capital: u64,
-    capacity: u32,
-    max_dst: Option<f32>,
-    all_solutions: &Mutex<Vec<TradeSolution>>,
-) {
-    let bar = Arc::new(ProgressBar::new(query.len().try_into().unwrap()));
-
-    query.par_iter().for_each(|station1| {
-        let bar = bar.clone();
-        let commodities1 = all_commodities.get(&station1.id).unwrap().to_owned();
-        let station1_system = stations_systems_map
-            .get(&station1.name)
-            .expect("couldn't find system name");
-        {
-            for station2 in sample {
-                // skip self
-                if station2.id == station1.id {
-                    continue;
-                }
+    /// Returns true if the station, or the system it's in, has been excluded
+    fn excludes(&self, station: &Station) -> bool {
+        let station_name = station.name.to_lowercase();
+        if self.stations.contains(&station_name) || self.either.contains(&station_name) {
+            return true;
+        }
+        station.system_name.as_ref().is_some_and(|name| {
+            let system_name = name.to_lowercase();
+            self.systems.contains(&system_name) || self.either.contains(&system_name)
+        })
+    }
+}
 
-                // ensure the other station is within the max distance (if it was specified)
-                if let Some(dst) = max_dst {
-                    let station2_system = stations_systems_map
-                        .get(&station2.name)
-                        .expect("couldn't find system name");
-
-                    if station1_system
-                        .coords
-                        .geometry
-                        .unwrap()
-                        .dst(&station2_system.coords.geometry.unwrap())
-                        > dst.into()
-                    {
-                        continue;
-                    }
-                }
+/// Builds the lowercased names of commodities that should never be hauled from the command line,
+/// reading further names from `file` if given
+fn read_ignored_commodities(names: &[String], file: Option<&Path>) -> Result<HashSet<String>> {
+    let mut ignored: HashSet<String> = names.iter().map(|name| name.to_lowercase()).collect();
+    if let Some(path) = file {
+        ignored.extend(
+            std::fs::read_to_string(path)
+                .map_err(|err| {
+                    eyre!(
+                        "Could not read ignored commodity file {}: {err}",
+                        path.display()
+                    )
+                })?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_lowercase),
+        );
+    }
+    Ok(ignored)
+}
 
-                let commodities2 = all_commodities.get(&station2.id).unwrap().to_owned();
+/// Upper bounds of the market age buckets printed with `--show-data-age`, and their labels. Markets
+/// older than the last bound fall into a final "older" bucket.
+const DATA_AGE_BUCKETS: [(TimeDelta, &str); 4] = [
+    (TimeDelta::hours(1), "< 1 hour"),
+    (TimeDelta::days(1), "< 1 day"),
+    (TimeDelta::days(7), "< 7 days"),
+    (TimeDelta::days(30), "< 30 days"),
+];
 
-                let solution = solve_knapsack(
-                    StationMarket::new(station1.clone(), commodities1.clone()),
-                    StationMarket::new(station2.clone(), commodities2.clone()),
-                    capacity,
-                    capital,
-                );
+/// Width in characters of the longest bar in the `--show-data-age` histogram
+const DATA_AGE_BAR_WIDTH: usize = 40;
 
-                if let Some(sol) = solution {
-                    let mut access = all_solutions.lock().unwrap();
-                    access.push(sol.clone());
+/// Index of the `DATA_AGE_BUCKETS` bucket that a market of this age falls into, or one past the
+/// last bucket if it's older than all of them
+fn data_age_bucket(age: TimeDelta) -> usize {
+    DATA_AGE_BUCKETS
+        .iter()
+        .position(|(bound, _)| age < *bound)
+        .unwrap_or(DATA_AGE_BUCKETS.len())
+}
+
+/// Prints a histogram of how long ago each market was last updated, which is the age of its most
+/// recent listing
+fn print_data_age_histogram(all_commodities: &DashMap<i64, Vec<Commodity>>, now: NaiveDateTime) {
+    let mut counts = [0usize; DATA_AGE_BUCKETS.len() + 1];
+    for entry in all_commodities.iter() {
+        if let Some(listed_at) = entry.value().iter().map(|it| it.listed_at).max() {
+            counts[data_age_bucket(now - listed_at)] += 1;
+        }
+    }
+
+    let largest = counts.iter().copied().max().unwrap_or(0).max(1);
+    status!("{}", "📅 Age of sampled market data:".bold().fg::<Green>());
+    let labels = DATA_AGE_BUCKETS
+        .iter()
+        .map(|(_, label)| *label)
+        .chain(["older"]);
+    for (label, count) in labels.zip(counts) {
+        let bar = "█".repeat(count * DATA_AGE_BAR_WIDTH / largest);
+        status!(
+            "    {label:>9} {} {}",
+            bar.fg::<Orange>(),
+            count.separate_with_commas()
+        );
+    }
+}
+
+/// Wall-clock duration of each phase of `compute_single` in milliseconds, emitted with `--profile`
+#[derive(Debug, Default, Serialize)]
+struct PhaseTimings {
+    station_fetch_ms: f64,
+    commodity_fetch_ms: f64,
+    solve_ms: f64,
+    sort_ms: f64,
+    render_ms: f64,
+}
+
+/// Milliseconds elapsed since the given instant
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Approximate number of station pairs that the suggested sample factor aims for
+const TARGET_PAIR_COUNT: f64 = 1_000_000.0;
+
+/// Suggests a sample factor such that a galaxy-wide search over `station_count` stations evaluates
+/// approximately `TARGET_PAIR_COUNT` pairs
+fn suggest_sample_factor(station_count: usize) -> f32 {
+    if station_count == 0 {
+        return 1.0;
+    }
+    (TARGET_PAIR_COUNT.sqrt() / station_count as f64).min(1.0) as f32
+}
+
+/// Smallest random sample that is worth computing routes over
+const MIN_SAMPLE_SIZE: usize = 10;
+
+/// Computes how many of the `available` stations to randomly sample. A small factor over a small
+/// number of stations can round down to nothing, so the sample is bumped up to a floor (with a
+/// warning), and it is never larger than the number of stations available.
+fn compute_sample_size(factor: f32, available: usize) -> usize {
+    let size = (factor * available as f32).round() as usize;
+    let floor = MIN_SAMPLE_SIZE.min(available);
+    if size < floor {
+        warn!(
+            "A sample factor of {factor} would only sample {size} of {available} stations, \
+            sampling {floor} instead"
+        );
+        return floor;
+    }
+    size.min(available)
+}
+
+/// Number of station pairs above which the user must pass `--i-know-this-is-huge` to proceed
+const HUGE_PAIR_COUNT: usize = 100_000_000;
+
+/// Estimates the number of routes evaluated when each of `sources` stations is compared against
+/// `destinations` stations (which are assumed to include the source itself, so self routes like
+/// A->A are subtracted)
+fn estimate_pair_count(sources: usize, destinations: usize) -> usize {
+    sources.saturating_mul(destinations.saturating_sub(1))
+}
+
+/// Returns an error unless the estimated pair count is reasonable, or the user has confirmed they
+/// want to go ahead with a huge computation anyway
+fn check_pair_count(pairs: usize, confirmed: bool) -> Result<()> {
+    if pairs > HUGE_PAIR_COUNT && !confirmed {
+        return Err(eyre!(
+            "This computation would evaluate approximately {} station pairs, which may take days. \
+            Reduce --random-sample, or pass --i-know-this-is-huge to proceed anyway.",
+            pairs.separate_with_commas()
+        ));
+    }
+    Ok(())
+}
+
+/// Demand headroom ratio at which a destination is considered a "deep sink", and is no longer
+/// penalised when ranking with `--prefer-demand-headroom`
+const DEEP_SINK_HEADROOM: f64 = 10.0;
+
+lazy_static! {
+    /// Fleet carriers are named after their callsign, which is exactly two groups of three
+    /// uppercase letters or digits, like "K7Q-BQL". The whole name must match, so that stations
+    /// like "Abc-Def Orbital" aren't mistaken for carriers.
+    static ref FLEET_CARRIER_REGEX: Regex = Regex::new("^[A-Z0-9]{3}-[A-Z0-9]{3}$").unwrap();
+}
+
+/// Returns true if the station name is a fleet carrier
+fn is_fleet_carrier(name: &str) -> bool {
+    FLEET_CARRIER_REGEX.find(name).is_some()
+}
+
+/// Returns true if the station is allowed by the `--carriers` option
+fn carriers_allow(carriers: Carriers, station: &Station) -> bool {
+    match carriers {
+        Carriers::Exclude => !is_fleet_carrier(&station.name),
+        Carriers::Include => true,
+        Carriers::Only => is_fleet_carrier(&station.name),
+    }
+}
+
+/// Systems that are well known to need a permit, which are treated as permit-locked when the
+/// database doesn't record whether they are
+const KNOWN_PERMIT_SYSTEMS: [&str; 13] = [
+    "Sol",
+    "Achenar",
+    "Alioth",
+    "Shinrarta Dezhra",
+    "Sirius",
+    "Vega",
+    "Beta Hydri",
+    "Van Maanen's Star",
+    "Luyten 347-14",
+    "Mbooni",
+    "Hors",
+    "Isinor",
+    "Summerland",
+];
+
+/// Returns true if the station is in a system that needs a permit. The database is trusted where
+/// it records this. Otherwise, the well-known permit systems are assumed to need one, and any other
+/// system is assumed to need one only if `strict`.
+fn needs_permit(
+    station: &Station,
+    stations_systems_map: &HashMap<i64, Arc<System>>,
+    strict: bool,
+) -> bool {
+    if let Some(needs_permit) = stations_systems_map
+        .get(&station.id)
+        .and_then(|system| system.needs_permit)
+    {
+        return needs_permit;
+    }
+    station.system_name.as_ref().map_or(strict, |name| {
+        strict
+            || KNOWN_PERMIT_SYSTEMS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Factor between 0.0 and 1.0 that a trade's score is discounted by when the destination has little
+/// demand headroom, so that "deep sink" destinations are preferred
+fn demand_headroom_factor(trade: &TradeSolution) -> f64 {
+    trade.demand_headroom().min(DEEP_SINK_HEADROOM) / DEEP_SINK_HEADROOM
+}
+
+/// Distance in light years between the source and destination systems of a trade, if both are known
+fn trade_distance(
+    trade: &TradeSolution,
+    stations_systems_map: &HashMap<i64, Arc<System>>,
+) -> Option<f64> {
+    let source = stations_systems_map.get(&trade.source.id)?;
+    let destination = stations_systems_map.get(&trade.destination.id)?;
+    Some(source.coords.dst(&destination.coords))
+}
+
+/// Number of jumps needed to cover the distance with the given jump range, assuming every jump can
+/// be made in a straight line
+fn jump_count(distance: f64, jump_range: f32) -> u32 {
+    (distance / jump_range as f64).ceil() as u32
+}
+
+/// Distance in light seconds from the arrival star assumed for stations where it isn't known
+const ASSUMED_ARRIVAL_LS: f64 = 1_000.0;
+
+/// Rough estimate of the seconds spent in supercruise to reach a station this many light seconds
+/// from the arrival star. Ships keep accelerating the further they go, so this grows with the
+/// square root of the distance: about 45 seconds for 1,000 Ls, and 8 minutes for 100,000 Ls.
+fn supercruise_seconds(arrival_ls: f64) -> f64 {
+    1.5 * arrival_ls.sqrt()
+}
+
+/// Crude model of how long it takes to fly a route, for estimating profit per hour
+#[derive(Debug, Clone, Copy)]
+struct TimeModel {
+    jump_range: f32,
+    seconds_per_jump: f64,
+    seconds_per_dock: f64,
+}
+
+impl TimeModel {
+    /// Estimated seconds to fly from the source to the destination and back again, docking at
+    /// both, or None if the distance between them isn't known
+    fn round_trip_seconds(
+        &self,
+        trade: &TradeSolution,
+        stations_systems_map: &HashMap<i64, Arc<System>>,
+    ) -> Option<f64> {
+        let jumps = jump_count(
+            trade_distance(trade, stations_systems_map)?,
+            self.jump_range,
+        );
+        let supercruise = |station: &Station| {
+            supercruise_seconds(
+                station
+                    .distance_to_arrival
+                    .map_or(ASSUMED_ARRIVAL_LS, f64::from),
+            )
+        };
+        Some(
+            2.0 * (jumps as f64 * self.seconds_per_jump + self.seconds_per_dock)
+                + supercruise(&trade.source)
+                + supercruise(&trade.destination),
+        )
+    }
+
+    /// Estimated profit per hour of flying the route there and back, or None if the distance
+    /// between the stations isn't known
+    fn profit_per_hour(
+        &self,
+        trade: &TradeSolution,
+        stations_systems_map: &HashMap<i64, Arc<System>>,
+    ) -> Option<f64> {
+        let seconds = self.round_trip_seconds(trade, stations_systems_map)?;
+        Some(trade.total_profit() / seconds * 3_600.0)
+    }
+}
+
+/// Best profit below which a run's results are considered disappointing
+const LOW_PROFIT_THRESHOLD: f64 = 500_000.0;
+
+/// Number of candidate stations below which the candidate set is considered sparse
+const SPARSE_STATION_COUNT: usize = 50;
+
+/// If the best trade found was poor and there were few candidate stations to choose from, prints
+/// some suggestions for how the user could widen their search
+fn suggest_wider_search(
+    best_profit: Option<f64>,
+    candidates: usize,
+    landing_pad: LandingPad,
+    max_dst: Option<f32>,
+) {
+    if best_profit.unwrap_or(0.0) >= LOW_PROFIT_THRESHOLD || candidates >= SPARSE_STATION_COUNT {
+        return;
+    }
+
+    status!(
+        "{}",
+        format!(
+            "⚠️ Only {candidates} candidate stations were found for a {landing_pad:?} landing pad, \
+            and the best route makes less than {} CR.",
+            LOW_PROFIT_THRESHOLD.separate_with_commas()
+        )
+        .fg::<DarkOrange>()
+    );
+    if landing_pad != LandingPad::Small {
+        status!("    Try a smaller --landing-pad, if you have a ship that can use it.");
+    }
+    if let Some(dst) = max_dst {
+        status!("    Try widening --max-dst beyond {dst} LY.");
+    }
+}
+
+/// Key used to rank trades, from worst to best
+fn rank_key(
+    trade: &TradeSolution,
+    sort_by: SortBy,
+    prefer_demand_headroom: bool,
+    stations_systems_map: &HashMap<i64, Arc<System>>,
+    time_model: Option<&TimeModel>,
+) -> OrderedFloat<f64> {
+    let score = match sort_by {
+        SortBy::Profit => trade.total_profit(),
+        SortBy::Roi => trade.roi(),
+        SortBy::ProfitPerUnit => trade.profit_per_unit(),
+        // shorter routes are better, and routes of unknown length are the worst of all
+        SortBy::Distance => {
+            return OrderedFloat(
+                trade_distance(trade, stations_systems_map).map_or(f64::NEG_INFINITY, |dst| -dst),
+            )
+        }
+        // routes of unknown length can't be timed, so they're the worst of all
+        SortBy::TimeEfficiency => {
+            match time_model.and_then(|model| model.profit_per_hour(trade, stations_systems_map)) {
+                Some(profit_per_hour) => profit_per_hour,
+                None => return OrderedFloat(f64::NEG_INFINITY),
+            }
+        }
+    };
+    let score = score * trade.freshness.unwrap_or(1.0);
+    OrderedFloat(if prefer_demand_headroom {
+        score * demand_headroom_factor(trade)
+    } else {
+        score
+    })
+}
+
+/// Factor between 0.0 and 1.0 that a trade's score is scaled by with `--freshness-weight`. It
+/// decays linearly from 1.0 for a listing updated just now to 0.0 for one `expiry_days` old, and is
+/// based on the oldest listing bought from or sold to in the trade.
+fn freshness_factor(
+    trade: &TradeSolution,
+    all_commodities: &DashMap<i64, Vec<Commodity>>,
+    now: NaiveDateTime,
+    expiry_days: u32,
+) -> f64 {
+    let listed_at = |station_id: i64, name: &str| {
+        all_commodities.get(&station_id).and_then(|commodities| {
+            commodities
+                .iter()
+                .find(|commodity| commodity.name == name)
+                .map(|commodity| commodity.listed_at)
+        })
+    };
+    let oldest = trade
+        .buy
+        .iter()
+        .filter(|order| order.count > 0)
+        .flat_map(|order| {
+            [
+                listed_at(trade.source.id, &order.commodity_name),
+                listed_at(trade.destination.id, &order.commodity_name),
+            ]
+        })
+        .flatten()
+        .min();
+
+    // a trade with no known listings can't be judged, so it isn't penalised
+    let Some(oldest) = oldest else {
+        return 1.0;
+    };
+    let age_days = (now - oldest).num_seconds() as f64 / 86_400.0;
+    (1.0 - age_days / expiry_days as f64).clamp(0.0, 1.0)
+}
+
+/// Builds the thread pool that trades are solved on, with the given number of threads, or one per
+/// core if unset
+fn solve_thread_pool(threads: Option<u32>) -> Result<ThreadPool> {
+    Ok(ThreadPoolBuilder::new()
+        // zero means one thread per core to rayon
+        .num_threads(threads.map_or(0, |threads| threads as usize))
+        .build()?)
+}
+
+/// Opens where a final report is written, which is either the given file with colours stripped, or
+/// stdout
+fn report_writer(output: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match output {
+        Some(path) => Box::new(AutoStream::never(File::create(path)?)),
+        None => Box::new(anstream::stdout()),
+    })
+}
+
+/// Computes a single hop route
+pub async fn compute_single(args: ComputeSingleArgs) -> Result<()> {
+    if args.format != OutputFormat::Text {
+        QUIET.store(true, Ordering::Relaxed);
+    }
+    status!("Setting up PostgreSQL pool on {}", args.url.fg::<Orange>());
+    let pool = PgPoolOptions::new()
+        .max_connections(args.db_connections)
+        .connect(&args.url)
+        .await?;
+
+    // make sure the pool is closed on every exit path, not just on success
+    let result = compute_single_for_pads(&pool, args).await;
+    pool.close().await;
+    result
+}
+
+/// Runs the whole computation once for each landing pad, printing a section per pad
+async fn compute_single_for_pads(pool: &Pool<Postgres>, mut args: ComputeSingleArgs) -> Result<()> {
+    if args.eddn {
+        status!(
+            "Listening to EDDN on {} for {} seconds",
+            args.eddn_relay.fg::<Orange>(),
+            args.eddn_duration.fg::<Orange>()
+        );
+        let stats = eddn::ingest(
+            pool,
+            &args.eddn_relay,
+            Duration::from_secs(args.eddn_duration),
+        )
+        .await?;
+        status!(
+            "Saved {} listings from {} market updates",
+            stats.listings.separate_with_commas().fg::<Green>(),
+            stats.markets.separate_with_commas().fg::<Green>()
+        );
+    }
+
+    let landing_pads: Vec<LandingPad> = args.landing_pads.iter().copied().unique().collect();
+    if landing_pads.len() > 1 {
+        // share one seed between the pads, so that their results are comparable
+        args.seed.get_or_insert_with(rand::random);
+    }
+
+    for landing_pad in landing_pads.iter().copied() {
+        if landing_pads.len() > 1 {
+            status!(
+                "{}",
+                format!("🛬 {landing_pad:?} landing pad")
+                    .bold()
+                    .fg::<Green>()
+            );
+        }
+        compute_single_with_pool(pool, args.clone(), landing_pad).await?;
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            warn!("Skipping any remaining landing pads, since the solve was interrupted");
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Break out of compute_single that runs the computation for one landing pad on an already
+/// connected pool
+async fn compute_single_with_pool(
+    pool: &Pool<Postgres>,
+    args: ComputeSingleArgs,
+    landing_pad: LandingPad,
+) -> Result<()> {
+    let ComputeSingleArgs {
+        url: _,
+        capital,
+        reserve,
+        capacity,
+        min_unit_profit,
+        min_supply,
+        min_profit,
+        min_roi,
+        src,
+        src_search_ly,
+        max_dst,
+        jump_range,
+        max_jumps,
+        seconds_per_jump,
+        seconds_per_dock,
+        random_sample: sample_factor,
+        seed,
+        sample_strategy,
+        landing_pads: _,
+        fetch_concurrency,
+        db_connections: _,
+        db_retries,
+        cache,
+        use_cache,
+        checkpoint,
+        checkpoint_every,
+        resume_from,
+        max_arrival_distance,
+        allow_unknown_arrival,
+        also_consider_pad,
+        carriers,
+        exclude_systems,
+        exclude_stations,
+        exclude_file,
+        station_types,
+        exclude_permit_locked,
+        strict_permits,
+        ships,
+        sweep_capacity,
+        expiry,
+        assume_missing_sellable,
+        with_return_profit,
+        round_trip,
+        bidirectional_only,
+        commodities,
+        ignored_commodities,
+        ignore_commodity_file,
+        baseline_commodity,
+        find_loops,
+        max_loop_legs,
+        prefer_demand_headroom,
+        freshness_weight,
+        threads,
+        sort_by,
+        sort_direction,
+        top,
+        deadline,
+        dry_run,
+        eddn: _,
+        eddn_duration: _,
+        eddn_relay: _,
+        i_know_this_is_huge,
+        output,
+        tui,
+        json_out,
+        csv_out,
+        export_matrix,
+        format,
+        number_format,
+        solver,
+        profile,
+        solver_stats,
+        show_data_age,
+    } = args;
+    set_number_format(number_format);
+    let mut timings = PhaseTimings::default();
+    let thread_pool = solve_thread_pool(threads)?;
+
+    // the reserve is never spent, so everything from here on only sees what's left of the capital
+    let gross_capital = capital;
+    let capital = gross_capital.saturating_sub(reserve);
+    status!(
+        "Capital: {} CR, of which {} CR can be spent after a reserve of {} CR",
+        gross_capital.separate_with_commas().fg::<Orange>(),
+        capital.separate_with_commas().fg::<Green>(),
+        reserve.separate_with_commas().fg::<Orange>()
+    );
+
+    // a route takes at most max_jumps jumps exactly when it's no longer than max_jumps full jumps,
+    // so the jump limit can be enforced as a distance limit
+    let max_dst = match (max_jumps, jump_range) {
+        (Some(jumps), Some(range)) => {
+            let reach = jumps as f32 * range;
+            Some(max_dst.map_or(reach, |dst| dst.min(reach)))
+        }
+        _ => max_dst,
+    };
+
+    let date_cutoff = date_cutoff(expiry);
+
+    status!("Fetching all stations");
+    let start = Instant::now();
+    let (mut stations, mut stations_systems_map) = get_all_stations(pool, landing_pad).await?;
+
+    // stations which are only reachable with the --also-consider-pad landing pad
+    let mut alternate_pad_stations: HashSet<i64> = HashSet::new();
+    if let Some(pad) = also_consider_pad {
+        let primary: HashSet<i64> = stations.iter().map(|station| station.id).collect();
+        let (alternate_stations, alternate_systems) = get_all_stations(pool, pad).await?;
+        stations_systems_map.extend(alternate_systems);
+        for station in alternate_stations {
+            if !primary.contains(&station.id) {
+                alternate_pad_stations.insert(station.id);
+                stations.push(station);
+            }
+        }
+        status!(
+            "...including {} extra stations with a {:?} landing pad",
+            alternate_pad_stations.len().fg::<Orange>(),
+            pad
+        );
+    }
+    let exclusions = Exclusions::new(&exclude_systems, &exclude_stations, exclude_file.as_deref())?;
+    if !exclusions.is_empty() {
+        stations.retain(|station| !exclusions.excludes(station));
+        status!(
+            "...of which {} remain after exclusions",
+            stations.len().fg::<Orange>()
+        );
+    }
+    if !station_types.is_empty() {
+        let station_types: HashSet<String> =
+            station_types.iter().map(|it| it.to_lowercase()).collect();
+        // the type is NULL for stations that no type has been recorded for yet. dropping those would
+        // silently hide a large part of the galaxy, so they're kept
+        stations.retain(|station| {
+            station
+                .station_type
+                .as_ref()
+                .is_none_or(|it| station_types.contains(&it.to_lowercase()))
+        });
+        status!(
+            "...of which {} are of an allowed station type",
+            stations.len().fg::<Orange>()
+        );
+    }
+    if exclude_permit_locked {
+        stations.retain(|station| !needs_permit(station, &stations_systems_map, strict_permits));
+        status!(
+            "...of which {} don't need a permit",
+            stations.len().fg::<Orange>()
+        );
+    }
+    if let Some(max_arrival) = max_arrival_distance {
+        stations.retain(|station| match station.distance_to_arrival {
+            Some(distance) => distance <= max_arrival,
+            None => allow_unknown_arrival,
+        });
+        status!(
+            "...of which {} are within {} Ls of the arrival star",
+            stations.len().fg::<Orange>(),
+            max_arrival.fg::<Orange>()
+        );
+    }
+    timings.station_fetch_ms = elapsed_ms(start);
+
+    // a resumed run has to sample the same stations as the run it's resuming, so reuse its seed
+    let resumed = match &resume_from {
+        Some(path) => {
+            let checkpoint = read_checkpoint(path)?;
+            status!(
+                "Resuming from {}, with {} source stations already solved",
+                path.display().fg::<Orange>(),
+                checkpoint.completed.len().fg::<Orange>()
+            );
+            Some(checkpoint)
+        }
+        None => None,
+    };
+
+    // use SmallRng for speed. when no seed is given, pick one at random, and print it so that an
+    // interesting run can be reproduced
+    let seed = match (&resumed, seed) {
+        (Some(resumed), Some(seed)) if seed != resumed.seed => {
+            return Err(eyre!(
+                "The checkpoint was made with --seed {}, so it can't be resumed with --seed {seed}",
+                resumed.seed
+            ))
+        }
+        (Some(resumed), _) => resumed.seed,
+        (None, seed) => seed.unwrap_or_else(rand::random),
+    };
+    status!(
+        "Using random seed {} (pass --seed {seed} to reproduce this run)",
+        seed.fg::<Orange>()
+    );
+    let mut rng = SmallRng::seed_from_u64(seed);
+    // ensure that we are only selecting stations that have a market and system attached to
+    // them
+    let valid_stations: Vec<Station> = stations
+        .iter()
+        .filter(|station| {
+            station.market_id.is_some()
+                && station.system_id.is_some()
+                && carriers_allow(carriers, station)
+        })
+        .cloned()
+        .collect();
+
+    let suggested_factor = suggest_sample_factor(valid_stations.len());
+    status!(
+        "Suggested sample factor for {} stations: {}",
+        valid_stations.len().fg::<Orange>(),
+        suggested_factor.fg::<Orange>()
+    );
+    let sample_factor = sample_factor.unwrap_or(suggested_factor);
+
+    // the galaxy is very large, so randomly sample a number of stations
+    let sample_size = compute_sample_size(sample_factor, valid_stations.len());
+    status!(
+        "Computing random sample, factor: {} ({} stations)",
+        sample_factor.fg::<Orange>(),
+        sample_size.fg::<Orange>()
+    );
+
+    // when routes start near a fixed source and are limited in length, only stations within reach
+    // of the source can possibly be a destination, so don't waste the sample on any others
+    let mut system_cache = SystemCache::default();
+    let sampleable_stations: Vec<Station> = match (&src, max_dst) {
+        (Some(source), Some(dst)) => {
+            let reach = dst + src_search_ly.unwrap_or(0.0);
+            status!(
+                "Restricting random sample to stations within {} LY of {}",
+                reach.fg::<Orange>(),
+                source.fg::<Orange>()
+            );
+            let source_system = system_cache.get_system_by_name(pool, source).await?;
+            let systems: HashSet<String> =
+                get_all_systems_in_range(pool, &source_system, reach.into())
+                    .await?
+                    .iter()
+                    .map(|x| x.name.clone())
+                    .collect();
+            valid_stations
+                .into_iter()
+                .filter(|x| {
+                    x.system_name
+                        .as_ref()
+                        .is_some_and(|it| systems.contains(it))
+                })
+                .collect()
+        }
+        _ => valid_stations,
+    };
+
+    // now we can compute the random subsample
+    let mut random_sample: Vec<Station> = match sample_strategy {
+        SampleStrategy::Uniform => sampleable_stations
+            .iter()
+            .choose_multiple(&mut rng, sample_size)
+            .iter()
+            .map(|it| (*it).clone())
+            .collect(),
+        strategy => {
+            status!("Weighing stations for the sample by {strategy:?}");
+            let weights = get_market_weights(pool, strategy, &date_cutoff).await?;
+            let weight = |station: &Station| {
+                station
+                    .market_id
+                    .and_then(|id| weights.get(&id))
+                    .copied()
+                    .unwrap_or(0.0)
+            };
+            sampleable_stations
+                .choose_multiple_weighted(&mut rng, sample_size, weight)
+                .map_err(|err| eyre!("Could not take a weighted sample of stations: {err}"))?
+                .cloned()
+                .collect()
+        }
+    };
+
+    let average_sell_prices = if assume_missing_sellable {
+        status!("Computing galactic average sell prices");
+        Some(get_average_sell_prices(pool, &date_cutoff).await?)
+    } else {
+        None
+    };
+
+    let only_commodities: Option<HashSet<String>> = (!commodities.is_empty())
+        .then(|| commodities.iter().map(|name| name.to_lowercase()).collect());
+    let params = SolveParams {
+        capacity,
+        capital,
+        min_unit_profit,
+        min_supply,
+        estimated_sell_prices: average_sell_prices.as_ref(),
+        min_profit,
+        min_roi,
+        only_commodities: only_commodities.as_ref(),
+        solver,
+    };
+    let matrix_exporter = match export_matrix {
+        Some(ref path) => Some(MatrixExporter::new(path)?),
+        None => None,
+    };
+    let match_stats = MatchStats::default();
+    let deadline = Deadline::new(deadline);
+
+    // stations to compute routes from, which are either those near the source, or the whole sample
+    let query_stations: Vec<Station> = match src {
+        Some(ref source) => {
+            let stations_filtered: Vec<Station> = if let Some(dst) = src_search_ly {
+                // not a fixed source set, search within 'dst' LY of the source system
+                let source_system = system_cache
+                    .get_system_by_name(pool, src.as_ref().expect("src must be specified"))
+                    .await?;
+
+                status!(
+                    "Finding acceptable systems in {} LY range of {}",
+                    dst.fg::<Orange>(),
+                    source.fg::<Orange>()
+                );
+                let systems_in_range =
+                    get_all_systems_in_range(pool, &source_system, dst.into()).await?;
+                let systems: HashSet<String> =
+                    systems_in_range.iter().map(|x| x.name.clone()).collect();
+                status!(
+                    "...found {} acceptable systems",
+                    systems.len().fg::<Orange>()
+                );
+
+                // log each stage of the filtering, so it's clear where candidates are being lost
+                let system_ids: Vec<i64> = systems_in_range.iter().map(|x| x.id).collect();
+                status!(
+                    "...containing {} stations",
+                    count_stations_in_systems(pool, &system_ids)
+                        .await?
+                        .fg::<Orange>()
+                );
+                let pad_filtered: Vec<&Station> = stations
+                    .iter()
+                    .filter(|x| {
+                        x.system_name
+                            .as_ref()
+                            .is_some_and(|it| systems.contains(it))
+                    })
+                    .collect();
+                status!(
+                    "...of which {} have a suitable landing pad",
+                    pad_filtered.len().fg::<Orange>()
+                );
+                let carrier_filtered: Vec<Station> = pad_filtered
+                    .into_iter()
+                    .filter(|x| carriers_allow(carriers, x))
+                    .cloned()
+                    .collect();
+                match carriers {
+                    Carriers::Exclude => status!(
+                        "...of which {} are not fleet carriers",
+                        carrier_filtered.len().fg::<Orange>()
+                    ),
+                    Carriers::Only => status!(
+                        "...of which {} are fleet carriers",
+                        carrier_filtered.len().fg::<Orange>()
+                    ),
+                    Carriers::Include => {}
+                }
+
+                carrier_filtered
+                // TODO randomly subsample stations_filtered further? if it's a large number?
+            } else {
+                // fixed source set, pinned to a particular system
+                status!("Filtering all stations to fixed starting system '{source}'");
+                let pinned: Vec<Station> = stations
+                    .iter()
+                    .filter(|x| {
+                        x.system_name
+                            .as_ref()
+                            .is_some_and(|s| s.to_lowercase() == source.to_lowercase())
+                            && carriers_allow(carriers, x)
+                    })
+                    .map(|x| (*x).clone())
+                    .collect();
+                status!(
+                    "...found {} stations with a suitable landing pad",
+                    pinned.len().fg::<Orange>()
+                );
+                pinned
+            };
+
+            if stations_filtered.is_empty() {
+                return Err(eyre!(
+                    "No candidate stations remain near '{source}' after filtering"
+                ));
+            }
+
+            // extend the random sample with our fixed subsample (for when we do market lookup)
+            random_sample.extend(stations_filtered.iter().cloned());
+            stations_filtered
+        }
+
+        // no fixed source set, so we compare every station with every other station in the sample
+        None => random_sample.clone(),
+    };
+
+    check_pair_count(
+        estimate_pair_count(query_stations.len(), random_sample.len()),
+        // a dry run only reports the size of the computation, so it can always go ahead
+        i_know_this_is_huge || dry_run,
+    )?;
+
+    let mut sampled: Vec<i64> = random_sample.iter().map(|station| station.id).collect();
+    sampled.sort_unstable();
+    let run = CheckpointRun {
+        capital,
+        capacity,
+        landing_pad,
+        expiry,
+        src: src.clone(),
+        sampled,
+    };
+    if let Some(resumed) = &resumed {
+        run.check_resumes(&resumed.run)?;
+    }
+
+    status!(
+        "Retrieving all commodities for {} sampled stations",
+        random_sample.len().fg::<Orange>()
+    );
+    let start = Instant::now();
+    let all_commodities = get_all_commodities_cached(
+        &random_sample,
+        pool,
+        &date_cutoff,
+        FetchOptions {
+            concurrency: fetch_concurrency as usize,
+            retries: db_retries,
+        },
+        landing_pad,
+        cache.as_deref(),
+        use_cache,
+    )
+    .await?;
+    timings.commodity_fetch_ms = elapsed_ms(start);
+    // every fetched station gets an entry, so it's empty markets that show the cutoff left nothing
+    if all_commodities.iter().all(|entry| entry.value().is_empty()) {
+        return Err(eyre!("No commodities could be found after applying filtering. Maybe adjust your date cutoff?"));
+    }
+
+    if show_data_age {
+        print_data_age_histogram(&all_commodities, Utc::now().naive_utc());
+    }
+
+    // ignored commodities are stripped from every market up front, so that they're never bought,
+    // sold, or used for baselines and return legs
+    let ignored_commodities =
+        read_ignored_commodities(&ignored_commodities, ignore_commodity_file.as_deref())?;
+    if !ignored_commodities.is_empty() {
+        for mut entry in all_commodities.iter_mut() {
+            entry.retain(|commodity| !ignored_commodities.contains(&commodity.name.to_lowercase()));
+        }
+    }
+
+    // let the user know if they asked for a commodity that isn't traded anywhere we looked, since
+    // it's most likely a typo
+    if let Some(ref names) = only_commodities {
+        let mut listed: HashSet<String> = HashSet::new();
+        for entry in all_commodities.iter() {
+            listed.extend(
+                entry
+                    .value()
+                    .iter()
+                    .map(|commodity| commodity.name.to_lowercase()),
+            );
+        }
+        for name in names.difference(&listed) {
+            warn!("Commodity '{name}' was not found in any sampled market");
+        }
+    }
+
+    match src {
+        Some(ref source) => status!(
+            "Computing trades for approx {} stations ({} '{source}'{})",
+            query_stations.len().fg::<Orange>(),
+            "with fixed start location".fg::<DarkOrange>(),
+            if let Some(dst) = src_search_ly {
+                format!(" and within {dst} LY")
+                    .fg::<DarkOrange>()
+                    .to_string()
+            } else {
+                "".to_string()
+            }
+        ),
+        None => status!(
+            "Computing trades for {} stations (approx {} individual routes{})",
+            random_sample.len().fg::<Orange>(),
+            // this is because its stations^2 minus self intersecting routes (like going from
+            // A->A)
+            estimate_pair_count(random_sample.len(), random_sample.len()).fg::<Green>(),
+            // pairs further apart than this are skipped in do_solve
+            if let Some(dst) = max_dst {
+                format!(", at most {dst} LY apart")
+                    .fg::<DarkOrange>()
+                    .to_string()
+            } else {
+                "".to_string()
+            }
+        ),
+    }
+
+    if dry_run {
+        let with_commodities = all_commodities
+            .iter()
+            .filter(|entry| !entry.value().is_empty())
+            .count();
+        println!("{}", "🧪 Dry run, skipping the solve:".bold().fg::<Green>());
+        println!(
+            "    {} stations sampled, of which {} have commodities",
+            random_sample.len().fg::<Orange>(),
+            with_commodities.fg::<Orange>()
+        );
+        println!(
+            "    approx {} station pairs would be evaluated",
+            estimate_pair_count(query_stations.len(), random_sample.len())
+                .separate_with_commas()
+                .fg::<Green>()
+        );
+        return Ok(());
+    }
+
+    let station_coords = station_coordinates(&stations_systems_map);
+    let (completed, resumed_solutions) = resumed
+        .map(|it| (it.completed, it.solutions))
+        .unwrap_or_default();
+    let pending_stations: Vec<Station> = query_stations
+        .iter()
+        .filter(|station| !completed.contains(&station.id))
+        .cloned()
+        .collect();
+    let checkpointer = checkpoint.as_ref().map(|path| {
+        Checkpointer::new(
+            path.clone(),
+            checkpoint_every as usize,
+            Checkpoint {
+                seed,
+                run,
+                completed,
+                solutions: resumed_solutions.clone(),
+            },
+        )
+    });
+    let solving = Solving::start();
+    let start = Instant::now();
+    let mut solutions = thread_pool.install(|| {
+        let mut solutions = do_solve(
+            &pending_stations,
+            &random_sample,
+            &all_commodities,
+            &station_coords,
+            &params,
+            max_dst,
+            &SolveContext {
+                match_stats: &match_stats,
+                matrix_exporter: matrix_exporter.as_ref(),
+                deadline: &deadline,
+                checkpointer: checkpointer.as_ref(),
+            },
+        );
+        solutions.extend(resumed_solutions);
+        if round_trip {
+            let outbound = std::mem::take(&mut solutions);
+            status!(
+                "Computing return legs for {} trades",
+                outbound.len().fg::<Orange>()
+            );
+            let round_trips: Vec<TradeSolution> = outbound
+                .into_par_iter()
+                .filter(|trade| trade.profit > 0.0)
+                .filter_map(|mut trade| {
+                    let return_leg = solve_return_leg(&trade, &all_commodities, &params)
+                        .filter(|reverse| reverse.profit > 0.0)?;
+                    trade.return_leg = Some(Box::new(return_leg));
+                    Some(trade)
+                })
+                .collect();
+            status!(
+                "...of which {} are profitable in both directions",
+                round_trips.len().fg::<Orange>()
+            );
+            solutions = round_trips;
+        } else if bidirectional_only {
+            status!(
+                "Checking {} trades for a profitable way back",
+                solutions.len().fg::<Orange>()
+            );
+            solutions = std::mem::take(&mut solutions)
+                .into_par_iter()
+                .filter(|trade| {
+                    solve_return_leg(trade, &all_commodities, &params)
+                        .is_some_and(|reverse| reverse.profit > 0.0)
+                })
+                .collect();
+            status!(
+                "...of which {} are profitable in both directions",
+                solutions.len().fg::<Orange>()
+            );
+        }
+        solutions
+    });
+    drop(solving);
+    timings.solve_ms = elapsed_ms(start);
+
+    if let (Some(checkpointer), Some(path)) = (&checkpointer, &checkpoint) {
+        checkpointer.finish()?;
+        status!("Saved checkpoint to {}", path.display().fg::<Orange>());
+    }
+    if let (Some(exporter), Some(path)) = (&matrix_exporter, &export_matrix) {
+        exporter.finish()?;
+        status!("Exported route matrix to {}", path.display().fg::<Orange>());
+    }
+
+    if let (true, Some(expiry)) = (freshness_weight, expiry) {
+        let now = Utc::now().naive_utc();
+        for trade in &mut solutions {
+            trade.freshness = Some(freshness_factor(trade, &all_commodities, now, expiry));
+        }
+    }
+
+    let start = Instant::now();
+    let time_model = jump_range.map(|jump_range| TimeModel {
+        jump_range,
+        seconds_per_jump: seconds_per_jump.into(),
+        seconds_per_dock: seconds_per_dock.into(),
+    });
+    let rank = |x: &TradeSolution| {
+        rank_key(
+            x,
+            sort_by,
+            prefer_demand_headroom,
+            &stations_systems_map,
+            time_model.as_ref(),
+        )
+    };
+    let best_solutions: Vec<&TradeSolution> = solutions
+        .iter()
+        .sorted_by(|a, b| {
+            let by_rank = match sort_direction {
+                SortDirection::Asc => rank(a).cmp(&rank(b)),
+                SortDirection::Desc => rank(b).cmp(&rank(a)),
+            };
+            // solutions arrive in whatever order the workers finished in, so break ties by station
+            // so that equally good trades are listed in the same order every run
+            by_rank
+                .then_with(|| (a.source.id, a.destination.id).cmp(&(b.source.id, b.destination.id)))
+        })
+        .collect();
+    timings.sort_ms = elapsed_ms(start);
+
+    if deadline.was_hit() {
+        warn!(
+            "{} after evaluating {} station pairs, so these results are partial",
+            deadline.reason(),
+            match_stats.pairs()
+        );
+    }
+
+    let start = Instant::now();
+    let heading = match sort_direction {
+        SortDirection::Asc => "✨ Least optimal trades:",
+        SortDirection::Desc => "✨ Most optimal trades:",
+    };
+    let mut top_solutions: Vec<TradeSolution> = best_solutions
+        .iter()
+        .take(top)
+        .map(|x| (*x).clone())
+        .collect();
+    if let Some(name) = &baseline_commodity {
+        for trade in &mut top_solutions {
+            let source = all_commodities
+                .get(&trade.source.id)
+                .map(|it| it.to_owned());
+            let dest = all_commodities
+                .get(&trade.destination.id)
+                .map(|it| it.to_owned());
+            let profit = match (source, dest) {
+                (Some(source), Some(dest)) => single_commodity_profit(
+                    &StationMarket::new(trade.source.clone(), source),
+                    &StationMarket::new(trade.destination.clone(), dest),
+                    name,
+                    &params,
+                ),
+                _ => None,
+            };
+            trade.baseline = Some(Baseline {
+                commodity_name: name.clone(),
+                profit,
+            });
+        }
+    }
+    if let Some(range) = jump_range {
+        for trade in &mut top_solutions {
+            trade.jumps =
+                trade_distance(trade, &stations_systems_map).map(|dst| jump_count(dst, range));
+        }
+    }
+    if with_return_profit {
+        for trade in &mut top_solutions {
+            trade.return_profit =
+                solve_return_leg(trade, &all_commodities, &params).map(|reverse| reverse.profit);
+        }
+    }
+    if let Some(model) = &time_model {
+        for trade in &mut top_solutions {
+            trade.profit_per_hour = model.profit_per_hour(trade, &stations_systems_map);
+        }
+    }
+    match format {
+        OutputFormat::Text if solutions.is_empty() => {
+            explain_no_solutions(&match_stats, random_sample.len(), capital, min_unit_profit);
+        }
+        OutputFormat::Text => {
+            let mut out = report_writer(output.as_deref())?;
+            writeln!(out, "{}", heading.bold().fg::<Green>())?;
+            if deadline.was_hit() {
+                writeln!(
+                    out,
+                    "{}\n",
+                    format!(
+                        "⏱️ {}, so these are only the best trades found so far",
+                        deadline.reason()
+                    )
+                    .fg::<DarkOrange>()
+                )?;
+            }
+            for (i, trade) in top_solutions.iter().enumerate() {
+                let distance = trade_distance(trade, &stations_systems_map);
+                writeln!(
+                    out,
+                    "{}. {}",
+                    i + 1,
+                    trade.dump_coloured(pool, distance).await
+                )?;
+                if let Some(pad) = also_consider_pad {
+                    if alternate_pad_stations.contains(&trade.source.id)
+                        || alternate_pad_stations.contains(&trade.destination.id)
+                    {
+                        writeln!(
+                            out,
+                            "    {}",
+                            format!("⚠️ Requires a different ship/pad ({pad:?} landing pad)")
+                                .fg::<DarkOrange>()
+                        )?;
+                    }
+                }
+                writeln!(out)?;
+            }
+        }
+        OutputFormat::Json => {
+            let mut out = report_writer(output.as_deref())?;
+            write_json(&mut out, &top_solutions)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Csv => write_csv(report_writer(output.as_deref())?, &top_solutions)?,
+    }
+    if let Some(path) = &output {
+        status!("Wrote the best trades to {}", path.display().fg::<Orange>());
+    }
+    if tui {
+        browse(
+            best_solutions
+                .iter()
+                .map(|trade| BrowsedTrade {
+                    trade: (*trade).clone(),
+                    distance: trade_distance(trade, &stations_systems_map),
+                })
+                .collect(),
+        )?;
+    }
+
+    if find_loops {
+        // the main solve only covers trades starting near --src, but a loop may pass through any
+        // station in the region, so every pair in the sample needs solving
+        check_pair_count(
+            estimate_pair_count(random_sample.len(), random_sample.len()),
+            i_know_this_is_huge,
+        )?;
+        if let Some(loop_deadline) = deadline.follow_up("the search for trade loops") {
+            status!(
+                "Searching for trade loops between {} stations",
+                random_sample.len().fg::<Orange>()
+            );
+            let solving = Solving::start();
+            let region_solutions = thread_pool.install(|| {
+                do_solve(
+                    &random_sample,
+                    &random_sample,
+                    &all_commodities,
+                    &station_coords,
+                    &params,
+                    max_dst,
+                    &SolveContext::untracked(&MatchStats::default(), &loop_deadline),
+                )
+            });
+            drop(solving);
+
+            loop_deadline.note_cut_short("the search for trade loops");
+            match find_best_loop(&region_solutions, max_loop_legs.into(), None) {
+                Some(legs) => print_loop(pool, &legs, &stations_systems_map).await,
+                None => println!("No profitable trade loops could be found in this region"),
+            }
+        }
+    }
+
+    if let Some(path) = &json_out {
+        write_json(File::create(path)?, &top_solutions)?;
+        status!("Wrote trades as JSON to {}", path.display().fg::<Orange>());
+    }
+    if let Some(path) = &csv_out {
+        write_csv(File::create(path)?, &top_solutions)?;
+        status!("Wrote trades as CSV to {}", path.display().fg::<Orange>());
+    }
+
+    if !ships.is_empty() {
+        println!("{}", "🚀 Best route per ship:".bold().fg::<Green>());
+        // stations each landing pad size can dock at, so we only fetch each size once
+        let mut pad_stations: HashMap<LandingPad, HashSet<i64>> = HashMap::new();
+        for ship in &ships {
+            if let Entry::Vacant(entry) = pad_stations.entry(ship.landing_pad) {
+                let ids = get_all_stations(pool, ship.landing_pad)
+                    .await?
+                    .0
+                    .iter()
+                    .map(|station| station.id)
+                    .collect();
+                entry.insert(ids);
+            }
+            let dockable = &pad_stations[&ship.landing_pad];
+            let ship_query: Vec<Station> = query_stations
+                .iter()
+                .filter(|station| dockable.contains(&station.id))
+                .cloned()
+                .collect();
+            let ship_sample: Vec<Station> = random_sample
+                .iter()
+                .filter(|station| dockable.contains(&station.id))
+                .cloned()
+                .collect();
+
+            // each ship keeps the same reserve back from its own capital
+            let ship_capital = ship.capital.saturating_sub(reserve);
+            let ship_params = SolveParams {
+                capacity: ship.capacity,
+                capital: ship_capital,
+                ..params
+            };
+            let pass = format!("the route for {}", ship.name);
+            let Some(ship_deadline) = deadline.follow_up(&pass) else {
+                continue;
+            };
+            let solving = Solving::start();
+            let ship_solutions = thread_pool.install(|| {
+                do_solve(
+                    &ship_query,
+                    &ship_sample,
+                    &all_commodities,
+                    &station_coords,
+                    &ship_params,
+                    max_dst,
+                    &SolveContext::untracked(&MatchStats::default(), &ship_deadline),
+                )
+            });
+            drop(solving);
+
+            println!(
+                "{} ({}t, {} CR of which {} CR can be spent, {:?} pad):",
+                ship.name.bold(),
+                ship.capacity,
+                ship.capital.separate_with_commas(),
+                ship_capital.separate_with_commas(),
+                ship.landing_pad
+            );
+            ship_deadline.note_cut_short(&pass);
+            let best = ship_solutions.into_iter().max_by_key(|x| {
+                rank_key(
+                    x,
+                    sort_by,
+                    prefer_demand_headroom,
+                    &stations_systems_map,
+                    time_model.as_ref(),
+                )
+            });
+            match best {
+                Some(trade) => {
+                    let distance = trade_distance(&trade, &stations_systems_map);
+                    println!("{}", trade.dump_coloured(pool, distance).await)
+                }
+                None => println!("    No route found"),
+            }
+            println!();
+        }
+    }
+
+    if let Some(sweep) = sweep_capacity {
+        println!(
+            "{}",
+            "📦 Best route profit per capacity:".bold().fg::<Green>()
+        );
+        for capacity in sweep.capacities() {
+            let sweep_params = SolveParams { capacity, ..params };
+            let pass = format!("the route for {capacity}t");
+            let Some(sweep_deadline) = deadline.follow_up(&pass) else {
+                continue;
+            };
+            let solving = Solving::start();
+            let best = thread_pool
+                .install(|| {
+                    do_solve(
+                        &query_stations,
+                        &random_sample,
+                        &all_commodities,
+                        &station_coords,
+                        &sweep_params,
+                        max_dst,
+                        &SolveContext::untracked(&MatchStats::default(), &sweep_deadline),
+                    )
+                })
+                .into_iter()
+                .map(|trade| trade.profit)
+                .max_by(f64::total_cmp);
+            drop(solving);
+            match best {
+                Some(profit) => println!(
+                    "    {:>6}t  {} CR",
+                    capacity,
+                    profit.round().separate_with_commas().fg::<Green>()
+                ),
+                None => println!("    {capacity:>6}t  no route found"),
+            }
+            sweep_deadline.note_cut_short(&pass);
+        }
+        println!();
+    }
+    timings.render_ms = elapsed_ms(start);
+
+    status!(
+        "Commodity match rate: {} overlapping commodities per pair on average, {} of {} pairs had no overlap",
+        format!("{:.1}", match_stats.average_overlap()).fg::<Orange>(),
+        format!("{:.1}%", match_stats.zero_overlap_fraction() * 100.0).fg::<Orange>(),
+        match_stats
+            .pairs
+            .load(Ordering::Relaxed)
+            .separate_with_commas()
+            .fg::<Orange>()
+    );
+    status!(
+        "Skipped {} pairs where the source sells nothing the destination buys",
+        match_stats.pruned().separate_with_commas().fg::<Orange>()
+    );
+
+    suggest_wider_search(
+        solutions.iter().map(|x| x.profit).max_by(f64::total_cmp),
+        random_sample.len(),
+        landing_pad,
+        max_dst,
+    );
+
+    if profile {
+        eprintln!("{}", serde_json::to_string(&timings)?);
+    }
+    if solver_stats {
+        match_stats.print_solver_stats();
+    }
+
+    Ok(())
+}
+
+/// Solves the trade in the reverse direction, from the trade's destination back to its source,
+/// using the already fetched commodities
+fn solve_return_leg(
+    trade: &TradeSolution,
+    all_commodities: &DashMap<i64, Vec<Commodity>>,
+    params: &SolveParams<'_>,
+) -> Option<TradeSolution> {
+    let source_commodities = all_commodities.get(&trade.destination.id)?.to_owned();
+    let dest_commodities = all_commodities.get(&trade.source.id)?.to_owned();
+    skip_failed(solve_knapsack(
+        &StationMarket::new(trade.destination.clone(), source_commodities),
+        &StationMarket::new(trade.source.clone(), dest_commodities),
+        params,
+    ))
+}
+
+/// Logs and skips a trade that couldn't be solved, so that one bad station doesn't abort the run
+fn skip_failed(result: Result<Option<TradeSolution>, KuralError>) -> Option<TradeSolution> {
+    result.unwrap_or_else(|err| {
+        error!("Skipping trade: {err}");
+        None
+    })
+}
+
+/// Maximum number of outgoing trades from each station that are followed when searching for loops,
+/// which keeps the search tractable in large regions
+const LOOP_EDGES_PER_STATION: usize = 20;
+
+/// Graph of profitable trades between stations, used to search for loops
+struct TradeGraph<'a> {
+    /// Most profitable trades leaving each station, by source station ID
+    edges: HashMap<i64, Vec<&'a TradeSolution>>,
+    /// Every profitable trade, by (source, destination) station ID
+    pairs: HashMap<(i64, i64), &'a TradeSolution>,
+}
+
+impl<'a> TradeGraph<'a> {
+    fn new(solutions: &'a [TradeSolution]) -> Self {
+        let mut edges: HashMap<i64, Vec<&TradeSolution>> = HashMap::new();
+        let mut pairs = HashMap::new();
+        for trade in solutions.iter().filter(|trade| trade.profit > 0.0) {
+            edges.entry(trade.source.id).or_default().push(trade);
+            pairs.insert((trade.source.id, trade.destination.id), trade);
+        }
+        for trades in edges.values_mut() {
+            trades.sort_by(|a, b| b.profit.total_cmp(&a.profit));
+            trades.truncate(LOOP_EDGES_PER_STATION);
+        }
+        TradeGraph { edges, pairs }
+    }
+
+    /// Depth first search for loops back to `start` that extend `path`, recording the most
+    /// profitable one found in `best`. If `through` is given, only loops visiting at least one of
+    /// those stations are recorded.
+    fn visit(
+        &self,
+        start: i64,
+        path: &mut Vec<&'a TradeSolution>,
+        max_legs: usize,
+        through: Option<&HashSet<i64>>,
+        best: &mut Option<(f64, Vec<&'a TradeSolution>)>,
+    ) {
+        let current = path.last().map_or(start, |leg| leg.destination.id);
+
+        // try to close the loop, which needs at least 3 legs to not just be a round trip
+        if path.len() >= 2 {
+            let passes_through = through.is_none_or(|ids| {
+                ids.contains(&start) || path.iter().any(|leg| ids.contains(&leg.destination.id))
+            });
+            if let (Some(closing), true) = (self.pairs.get(&(current, start)), passes_through) {
+                let profit = path.iter().map(|leg| leg.profit).sum::<f64>() + closing.profit;
+                if best.as_ref().is_none_or(|(best, _)| profit > *best) {
+                    let mut legs = path.clone();
+                    legs.push(closing);
+                    *best = Some((profit, legs));
+                }
+            }
+        }
+
+        // leave room for the closing leg
+        if path.len() + 1 >= max_legs {
+            return;
+        }
+
+        for &leg in self.edges.get(&current).into_iter().flatten() {
+            let next = leg.destination.id;
+            // each loop is only found from its lowest station ID, and never revisits a station
+            if next <= start || path.iter().any(|visited| visited.destination.id == next) {
+                continue;
+            }
+            path.push(leg);
+            self.visit(start, path, max_legs, through, best);
+            path.pop();
+        }
+    }
+}
+
+/// Finds the most profitable loop of between 3 and `max_legs` trades that returns to the station
+/// it started from, where every leg is independently profitable. If `through` is given, the loop
+/// must visit one of those stations, and starts from it. Returns the legs in order.
+fn find_best_loop<'a>(
+    solutions: &'a [TradeSolution],
+    max_legs: usize,
+    through: Option<&HashSet<i64>>,
+) -> Option<Vec<&'a TradeSolution>> {
+    let graph = TradeGraph::new(solutions);
+    let mut best = None;
+    for &start in graph.edges.keys() {
+        graph.visit(start, &mut Vec::new(), max_legs, through, &mut best);
+    }
+    let (_, mut legs) = best?;
+    if let Some(ids) = through {
+        // loops are found from their lowest station ID, so rotate to start where asked
+        if let Some(first) = legs.iter().position(|leg| ids.contains(&leg.source.id)) {
+            legs.rotate_left(first);
+        }
+    }
+    Some(legs)
+}
+
+/// Prints a loop of trades with the orders of each leg, followed by the total profit and distance
+async fn print_loop(
+    pool: &Pool<Postgres>,
+    legs: &[&TradeSolution],
+    stations_systems_map: &HashMap<i64, Arc<System>>,
+) {
+    let total: f64 = legs.iter().map(|leg| leg.profit).sum();
+    println!(
+        "{} {} CR",
+        "🔁 Most profitable trade loop:".bold().fg::<Green>(),
+        total.round().separate_with_commas().fg::<Green>()
+    );
+    for (i, leg) in legs.iter().enumerate() {
+        let distance = trade_distance(leg, stations_systems_map);
+        println!("{}. {}", i + 1, leg.dump_coloured(pool, distance).await);
+        println!();
+    }
+
+    let distances: Option<Vec<f64>> = legs
+        .iter()
+        .map(|leg| trade_distance(leg, stations_systems_map))
+        .collect();
+    let distance = match distances {
+        Some(distances) => format!("{:.1} LY", distances.iter().sum::<f64>()),
+        None => "an unknown distance".to_string(),
+    };
+    println!(
+        "{} legs, {} CR profit in total, over {}",
+        legs.len().fg::<Orange>(),
+        total.round().separate_with_commas().fg::<Green>(),
+        distance.fg::<Orange>()
+    );
+}
+
+/// Names of the commodities each station sells (has in stock) and buys (has demand for), so that
+/// pairs of stations with nothing to trade can be skipped without setting up a solve
+#[derive(Debug, Default)]
+struct TradeIndex {
+    sells: HashMap<i64, HashSet<String>>,
+    buys: HashMap<i64, HashSet<String>>,
+}
+
+impl TradeIndex {
+    fn new<'a>(
+        stations: impl IntoIterator<Item = &'a Station>,
+        all_commodities: &DashMap<i64, Vec<Commodity>>,
+    ) -> Self {
+        let mut index = Self::default();
+        for station in stations {
+            if index.sells.contains_key(&station.id) {
+                continue;
+            }
+            let Some(commodities) = all_commodities.get(&station.id) else {
+                continue;
+            };
+            let names = |keep: fn(&Commodity) -> bool| {
+                commodities
+                    .iter()
+                    .filter(|commodity| keep(commodity))
+                    .map(|commodity| commodity.name.clone())
+                    .collect()
+            };
+            index.sells.insert(station.id, names(|it| it.stock > 0));
+            index.buys.insert(station.id, names(|it| it.demand > 0));
+        }
+        index
+    }
+
+    /// Returns true if the source sells at least one commodity that the destination buys
+    fn can_trade(&self, source: i64, destination: i64) -> bool {
+        match (self.sells.get(&source), self.buys.get(&destination)) {
+            (Some(sells), Some(buys)) => !sells.is_disjoint(buys),
+            _ => false,
+        }
+    }
+}
+
+/// Everything a call to `do_solve` reports to or checks in with as it goes, besides the trades
+/// it returns
+#[derive(Clone, Copy)]
+struct SolveContext<'a> {
+    match_stats: &'a MatchStats,
+    /// Records the outcome of every pair, if `--export-matrix` was given
+    matrix_exporter: Option<&'a MatrixExporter>,
+    deadline: &'a Deadline,
+    /// Records each completed source station, if `--checkpoint` was given
+    checkpointer: Option<&'a Checkpointer>,
+}
+
+impl<'a> SolveContext<'a> {
+    /// A context for the solves after the main one, whose statistics aren't reported, and which
+    /// only need to respect a deadline
+    fn untracked(match_stats: &'a MatchStats, deadline: &'a Deadline) -> Self {
+        Self {
+            match_stats,
+            matrix_exporter: None,
+            deadline,
+            checkpointer: None,
+        }
+    }
+}
+
+/// Break out of compute_single that actually computes the solution
+fn do_solve(
+    query: &[Station],
+    sample: &[Station],
+    all_commodities: &Arc<DashMap<i64, Vec<Commodity>>>,
+    station_coords: &HashMap<i64, Coordinate>,
+    params: &SolveParams<'_>,
+    max_dst: Option<f32>,
+    context: &SolveContext<'_>,
+) -> Vec<TradeSolution> {
+    let SolveContext {
+        match_stats,
+        matrix_exporter,
+        deadline,
+        checkpointer,
+    } = *context;
+    let bar = progress_bar(query.len());
+
+    // when sell prices can be estimated, the destination doesn't need to list a commodity to buy
+    // it, so there's nothing to prune. the matrix export records every pair with its real overlap,
+    // so pairs can't be pruned without leaving holes in it either
+    let index = (params.estimated_sell_prices.is_none() && matrix_exporter.is_none())
+        .then(|| TradeIndex::new(query.iter().chain(sample), all_commodities));
+
+    // each rayon worker accumulates the solutions it finds locally, and they're only combined at
+    // the end, so that workers never contend over a shared lock
+    let solutions = query
+        .par_iter()
+        .fold(Vec::new, |mut solutions, station1| {
+            if deadline.passed() {
+                bar.inc(1);
+                return solutions;
+            }
+            // the source's market is built once and then solved against every destination
+            let Some(source) = all_commodities
+                .get(&station1.id)
+                .map(|it| StationMarket::new(station1.clone(), it.to_owned()))
+            else {
+                bar.inc(1);
+                return solutions;
+            };
+            let station1_coords = station_coords.get(&station1.id);
+            let first_solution = solutions.len();
+            let mut finished = true;
+            for station2 in sample {
+                if deadline.passed() {
+                    finished = false;
+                    break;
+                }
+
+                // skip self
+                if station2.id == station1.id {
+                    continue;
                 }
+
+                // ensure the other station is within the max distance (if it was specified)
+                // stations whose system couldn't be located are skipped, since we can't tell how
+                // far away they are
+                if let Some(dst) = max_dst {
+                    match (station1_coords, station_coords.get(&station2.id)) {
+                        (Some(coords1), Some(coords2)) if coords1.dst(coords2) <= dst.into() => {}
+                        _ => continue,
+                    }
+                }
+
+                if index
+                    .as_ref()
+                    .is_some_and(|index| !index.can_trade(station1.id, station2.id))
+                {
+                    match_stats.record_pruned();
+                    continue;
+                }
+
+                let Some(destination) = all_commodities
+                    .get(&station2.id)
+                    .map(|it| StationMarket::new(station2.clone(), it.to_owned()))
+                else {
+                    continue;
+                };
+
+                let overlap = source.count_overlap(&destination);
+                match_stats.record(overlap);
+                match_stats.record_cheapest_profitable(
+                    cheapest_profitable_price(&source, &destination),
+                    params.capital,
+                );
+
+                let start = Instant::now();
+                let result = solve_knapsack(&source, &destination, params);
+                match_stats.record_solve(&result, start.elapsed());
+                let solution = skip_failed(result);
+
+                if let Some(exporter) = matrix_exporter {
+                    if let Err(err) =
+                        exporter.record(station1, station2, solution.as_ref(), overlap)
+                    {
+                        error!(
+                            "Could not export {} -> {}: {}",
+                            station1.name, station2.name, err
+                        );
+                    }
+                }
+
+                if let Some(sol) = solution.filter(|sol| params.accepts(sol)) {
+                    solutions.push(sol);
+                }
+            }
+            // a source cut short by the deadline isn't complete, so it'll be solved again on resume
+            if let (Some(checkpointer), true) = (checkpointer, finished) {
+                checkpointer.complete(station1.id, &solutions[first_solution..]);
             }
             bar.inc(1);
+            solutions
+        })
+        .reduce(Vec::new, |mut solutions, mut other| {
+            solutions.append(&mut other);
+            solutions
+        });
+
+    bar.finish();
+    solutions
+}
+
+/// A partial multi-hop route explored by the beam search in `compute_multi`
+#[derive(Debug, Clone)]
+struct Itinerary {
+    /// Station the route starts from
+    start: Station,
+    /// Trades made on each hop, in order
+    legs: Vec<TradeSolution>,
+    /// Capital available at the end of the route, after selling the cargo of the last leg
+    capital: u64,
+    /// Cumulative profit of every leg
+    profit: f64,
+}
+
+impl Itinerary {
+    /// Station the route currently ends at
+    fn current(&self) -> &Station {
+        self.legs.last().map_or(&self.start, |leg| &leg.destination)
+    }
+
+    /// Returns true if the route has already passed through the given system
+    fn has_visited(&self, system_name: &str) -> bool {
+        self.start.system_name.as_deref() == Some(system_name)
+            || self
+                .legs
+                .iter()
+                .any(|leg| leg.destination.system_name.as_deref() == Some(system_name))
+    }
+}
+
+/// Stations randomly sampled for compute_multi and find_loops, which may be pinned to a source system
+struct RegionSample {
+    /// Map of station names to the system each one is in, for every station that could be sampled
+    stations_systems_map: HashMap<i64, Arc<System>>,
+    station_coords: HashMap<i64, Coordinate>,
+    /// Stations in the source system, if one was given
+    pinned: Vec<Station>,
+    /// The random sample, which always includes the pinned stations
+    stations: Vec<Station>,
+}
+
+/// Randomly samples stations that aren't fleet carriers. If a source system is given, its stations
+/// are always included, and if a reach is also given, only stations within that many LY of it are
+/// sampled.
+async fn sample_region(
+    pool: &Pool<Postgres>,
+    landing_pad: LandingPad,
+    src: Option<&str>,
+    reach: Option<f64>,
+    sample_factor: Option<f32>,
+    seed: Option<u64>,
+) -> Result<RegionSample> {
+    println!("Fetching all stations");
+    let (all_stations, stations_systems_map) = get_all_stations(pool, landing_pad).await?;
+    let station_coords = station_coordinates(&stations_systems_map);
+    let mut candidates: Vec<Station> = all_stations
+        .into_iter()
+        .filter(|station| !is_fleet_carrier(&station.name))
+        .collect();
+
+    // pinned stations are kept out of the sample, so that they're only added to it once
+    let mut pinned: Vec<Station> = Vec::new();
+    if let Some(source) = src {
+        if let Some(reach) = reach {
+            let source_system = get_system_by_name(pool, source).await?;
+            let reachable: HashSet<i64> = get_all_systems_in_range(pool, &source_system, reach)
+                .await?
+                .iter()
+                .map(|system| system.id)
+                .collect();
+            candidates
+                .retain(|station| station.system_id.is_some_and(|id| reachable.contains(&id)));
+        }
+        (pinned, candidates) = candidates.into_iter().partition(|station| {
+            station
+                .system_name
+                .as_ref()
+                .is_some_and(|name| name.to_lowercase() == source.to_lowercase())
+        });
+        if pinned.is_empty() {
+            return Err(eyre!(
+                "No candidate stations remain in '{source}' after filtering"
+            ));
+        }
+    }
+
+    let sample_factor = sample_factor.unwrap_or(suggest_sample_factor(candidates.len()));
+    let sample_size = compute_sample_size(sample_factor, candidates.len());
+    println!(
+        "Computing random sample, factor: {} ({} stations)",
+        sample_factor.fg::<Orange>(),
+        sample_size.fg::<Orange>()
+    );
+    // like compute_single, print the seed so that an interesting run can be reproduced
+    let seed = seed.unwrap_or_else(rand::random);
+    println!(
+        "Using random seed {} (pass --seed {seed} to reproduce this run)",
+        seed.fg::<Orange>()
+    );
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut stations: Vec<Station> = candidates
+        .iter()
+        .choose_multiple(&mut rng, sample_size)
+        .into_iter()
+        .cloned()
+        .collect();
+    stations.extend(pinned.iter().cloned());
+
+    Ok(RegionSample {
+        stations_systems_map,
+        station_coords,
+        pinned,
+        stations,
+    })
+}
+
+/// Fetches the commodities of every sampled station, failing if none could be found
+async fn fetch_sample_commodities(
+    pool: &Pool<Postgres>,
+    sample: &[Station],
+    date_cutoff: &NaiveDateTime,
+    options: FetchOptions,
+) -> Result<Arc<DashMap<i64, Vec<Commodity>>>> {
+    println!(
+        "Retrieving all commodities for {} sampled stations",
+        sample.len().fg::<Orange>()
+    );
+    let all_commodities = get_all_commodities(sample, pool, date_cutoff, options).await?;
+    // every fetched station gets an entry, so it's empty markets that show the cutoff left nothing
+    if all_commodities.iter().all(|entry| entry.value().is_empty()) {
+        return Err(eyre!("No commodities could be found after applying filtering. Maybe adjust your date cutoff?"));
+    }
+    Ok(all_commodities)
+}
+
+/// Computes a multi-hop route
+pub async fn compute_multi(args: ComputeMultiArgs) -> Result<()> {
+    println!("Setting up PostgreSQL pool on {}", args.url.fg::<Orange>());
+    let pool = PgPoolOptions::new()
+        .max_connections(args.db_connections)
+        .connect(&args.url)
+        .await?;
+
+    let result = compute_multi_with_pool(&pool, args).await;
+    pool.close().await;
+    result
+}
+
+/// Break out of compute_multi that runs the computation on an already connected pool
+async fn compute_multi_with_pool(pool: &Pool<Postgres>, args: ComputeMultiArgs) -> Result<()> {
+    let ComputeMultiArgs {
+        url: _,
+        capital,
+        capacity,
+        min_unit_profit,
+        hops,
+        allow_revisit,
+        beam_width,
+        src,
+        max_dst,
+        random_sample: sample_factor,
+        seed,
+        i_know_this_is_huge,
+        landing_pad,
+        expiry,
+        number_format,
+        solver,
+        threads,
+        db_connections,
+        db_retries,
+    } = args;
+    let thread_pool = solve_thread_pool(threads)?;
+    set_number_format(number_format);
+
+    // routes pinned to a source can't get further from it than every hop's max distance allows
+    let reach = max_dst.map(|dst| dst as f64 * hops as f64);
+    let RegionSample {
+        stations_systems_map,
+        station_coords,
+        pinned,
+        stations: random_sample,
+    } = sample_region(
+        pool,
+        landing_pad,
+        src.as_deref(),
+        reach,
+        sample_factor,
+        seed,
+    )
+    .await?;
+    // the first hop extends every starting station, which is the whole sample without --src, and
+    // every later hop at most --beam-width routes
+    let starting_routes = if src.is_some() {
+        pinned.len()
+    } else {
+        random_sample.len()
+    };
+    let sample_pairs = |routes: usize| estimate_pair_count(routes, random_sample.len());
+    check_pair_count(
+        sample_pairs(starting_routes).saturating_add(
+            sample_pairs(beam_width).saturating_mul(hops.saturating_sub(1) as usize),
+        ),
+        i_know_this_is_huge,
+    )?;
+    let all_commodities = fetch_sample_commodities(
+        pool,
+        &random_sample,
+        &date_cutoff(expiry),
+        FetchOptions {
+            concurrency: db_connections as usize,
+            retries: db_retries,
+        },
+    )
+    .await?;
+
+    // routes either start from the stations in the source system, or anywhere in the sample
+    let starts: Vec<Station> = if src.is_some() {
+        pinned
+    } else {
+        random_sample.clone()
+    };
+
+    let mut beam: Vec<Itinerary> = starts
+        .into_iter()
+        .map(|start| Itinerary {
+            start,
+            legs: Vec::new(),
+            capital,
+            profit: 0.0,
+        })
+        .collect();
+
+    for hop in 1..=hops {
+        println!(
+            "Computing hop {} of {} from {} partial routes",
+            hop.fg::<Orange>(),
+            hops.fg::<Orange>(),
+            beam.len().fg::<Orange>()
+        );
+        let bar = progress_bar(beam.len());
+
+        let mut candidates: Vec<Itinerary> = thread_pool.install(|| {
+            beam.par_iter()
+                .flat_map_iter(|itinerary| {
+                    let extended = extend_itinerary(
+                        itinerary,
+                        &random_sample,
+                        &all_commodities,
+                        &station_coords,
+                        max_dst,
+                        allow_revisit,
+                        SolveParams {
+                            capacity,
+                            capital: itinerary.capital,
+                            min_unit_profit,
+                            min_supply: 0,
+                            estimated_sell_prices: None,
+                            min_profit: 0,
+                            min_roi: None,
+                            only_commodities: None,
+                            solver,
+                        },
+                    );
+                    bar.inc(1);
+                    extended
+                })
+                .collect()
+        });
+        bar.finish();
+
+        if candidates.is_empty() {
+            println!(
+                "{}",
+                format!("No profitable hop {hop} could be found, stopping early")
+                    .fg::<DarkOrange>()
+            );
+            break;
         }
+
+        // greedily keep only the most profitable partial routes
+        candidates.sort_by(|a, b| b.profit.total_cmp(&a.profit));
+        candidates.truncate(beam_width);
+        beam = candidates;
+    }
+
+    let Some(best) = beam.into_iter().next().filter(|it| !it.legs.is_empty()) else {
+        return Err(eyre!("No profitable routes could be found"));
+    };
+
+    println!(
+        "{}",
+        format!("✨ Most optimal {} hop route:", best.legs.len())
+            .bold()
+            .fg::<Green>()
+    );
+    let mut cumulative = 0.0;
+    for (i, leg) in best.legs.iter().enumerate() {
+        cumulative += leg.profit;
+        let distance = trade_distance(leg, &stations_systems_map);
+        println!("{}. {}", i + 1, leg.dump_coloured(pool, distance).await);
+        println!(
+            "    (cumulative profit {} CR)",
+            cumulative.round().separate_with_commas().fg::<Green>()
+        );
+        println!();
+    }
+
+    Ok(())
+}
+
+pub async fn find_loops(args: FindLoopsArgs) -> Result<()> {
+    println!("Setting up PostgreSQL pool on {}", args.url.fg::<Orange>());
+    let pool = PgPoolOptions::new()
+        .max_connections(args.db_connections)
+        .connect(&args.url)
+        .await?;
+
+    let result = find_loops_with_pool(&pool, args).await;
+    pool.close().await;
+    result
+}
+
+/// Break out of find_loops that runs the search on an already connected pool
+async fn find_loops_with_pool(pool: &Pool<Postgres>, args: FindLoopsArgs) -> Result<()> {
+    let FindLoopsArgs {
+        url: _,
+        capital,
+        capacity,
+        min_unit_profit,
+        max_legs,
+        src,
+        max_dst,
+        random_sample: sample_factor,
+        seed,
+        landing_pad,
+        expiry,
+        i_know_this_is_huge,
+        number_format,
+        solver,
+        threads,
+        db_connections,
+        db_retries,
+    } = args;
+    let thread_pool = solve_thread_pool(threads)?;
+    set_number_format(number_format);
+
+    // a loop through the source can't stray further from it than half its legs allow
+    let reach = Some(max_dst as f64 * (max_legs / 2) as f64);
+    let RegionSample {
+        stations_systems_map,
+        station_coords,
+        pinned,
+        stations: random_sample,
+    } = sample_region(
+        pool,
+        landing_pad,
+        src.as_deref(),
+        reach,
+        sample_factor,
+        seed,
+    )
+    .await?;
+    check_pair_count(
+        estimate_pair_count(random_sample.len(), random_sample.len()),
+        i_know_this_is_huge,
+    )?;
+    let all_commodities = fetch_sample_commodities(
+        pool,
+        &random_sample,
+        &date_cutoff(expiry),
+        FetchOptions {
+            concurrency: db_connections as usize,
+            retries: db_retries,
+        },
+    )
+    .await?;
+
+    let params = SolveParams {
+        capacity,
+        capital,
+        min_unit_profit,
+        min_supply: 0,
+        estimated_sell_prices: None,
+        min_profit: 0,
+        min_roi: None,
+        only_commodities: None,
+        solver,
+    };
+    println!(
+        "Searching for trade loops between {} stations",
+        random_sample.len().fg::<Orange>()
+    );
+    let solving = Solving::start();
+    let solutions = thread_pool.install(|| {
+        do_solve(
+            &random_sample,
+            &random_sample,
+            &all_commodities,
+            &station_coords,
+            &params,
+            Some(max_dst),
+            &SolveContext::untracked(&MatchStats::default(), &Deadline::new(None)),
+        )
     });
+    drop(solving);
 
-    bar.finish();
+    let through: Option<HashSet<i64>> = src
+        .is_some()
+        .then(|| pinned.iter().map(|station| station.id).collect());
+    match find_best_loop(&solutions, max_legs.into(), through.as_ref()) {
+        Some(legs) => print_loop(pool, &legs, &stations_systems_map).await,
+        None => println!("No profitable trade loops could be found in this region"),
+    }
+
+    Ok(())
+}
+
+/// Computes every profitable way of extending the itinerary by one hop to a station in the sample
+fn extend_itinerary(
+    itinerary: &Itinerary,
+    sample: &[Station],
+    all_commodities: &DashMap<i64, Vec<Commodity>>,
+    station_coords: &HashMap<i64, Coordinate>,
+    max_dst: Option<f32>,
+    allow_revisit: bool,
+    params: SolveParams<'_>,
+) -> Vec<Itinerary> {
+    let current = itinerary.current();
+    let Some(source) = all_commodities
+        .get(&current.id)
+        .map(|it| StationMarket::new(current.clone(), it.to_owned()))
+    else {
+        return Vec::new();
+    };
+
+    let mut out = Vec::new();
+    for destination in sample {
+        if destination.id == current.id {
+            continue;
+        }
+        if !allow_revisit
+            && destination
+                .system_name
+                .as_deref()
+                .is_some_and(|name| itinerary.has_visited(name))
+        {
+            continue;
+        }
+
+        // stations whose system couldn't be located are skipped, since we can't tell how far
+        // away they are
+        if let Some(dst) = max_dst {
+            match (
+                station_coords.get(&current.id),
+                station_coords.get(&destination.id),
+            ) {
+                (Some(coords1), Some(coords2)) if coords1.dst(coords2) <= dst.into() => {}
+                _ => continue,
+            }
+        }
+
+        let Some(dest_commodities) = all_commodities.get(&destination.id) else {
+            continue;
+        };
+        let Some(trade) = skip_failed(solve_knapsack(
+            &source,
+            &StationMarket::new(destination.clone(), dest_commodities.to_owned()),
+            &params,
+        )) else {
+            continue;
+        };
+        if trade.profit <= 0.0 {
+            continue;
+        }
+
+        let mut extended = itinerary.clone();
+        // the cargo is sold on arrival, so the profit is available to spend on the next hop
+        extended.capital = params.capital.saturating_add(trade.profit as u64);
+        extended.profit += trade.profit;
+        extended.legs.push(trade);
+        out.push(extended);
+    }
+    out
+}
+
+/// Looks up a single station by name, failing if there's no such station or the name is ambiguous
+async fn find_station(pool: &Pool<Postgres>, name: &str, system: Option<&str>) -> Result<Station> {
+    let mut stations = get_stations_by_name(pool, name, system).await?;
+    match stations.len() {
+        0 => Err(eyre!("No station named '{name}' could be found")),
+        1 => Ok(stations.remove(0)),
+        _ => {
+            let systems = stations
+                .iter()
+                .map(|station| {
+                    station
+                        .system_name
+                        .as_deref()
+                        .unwrap_or("an unknown system")
+                })
+                .join(", ");
+            Err(eyre!(
+                "There are stations named '{name}' in {systems}. Pick one with --src-system or \
+                --dst-system."
+            ))
+        }
+    }
+}
+
+/// Distance in light years between the systems of two stations, looked up in the database. This is
+/// only meant for one-off pairs, since the solve loops already know every station's coordinates.
+async fn station_distance(
+    pool: &Pool<Postgres>,
+    source: &Station,
+    destination: &Station,
+) -> Option<f64> {
+    let mut coords = Vec::with_capacity(2);
+    for station in [source, destination] {
+        let Some(system_name) = &station.system_name else {
+            warn!(
+                "Could not compute a distance, {} has no system",
+                station.name
+            );
+            return None;
+        };
+        match get_system_by_name(pool, system_name).await {
+            Ok(system) => coords.push(system.coords),
+            Err(err) => {
+                warn!("Could not look up the system of {}: {}", station.name, err);
+                return None;
+            }
+        }
+    }
+    Some(coords[0].dst(&coords[1]))
+}
+
+/// Solves the trade between a single pair of stations, printing the model given to the solver if
+/// asked to
+pub async fn solve_pair(args: SolvePairArgs) -> Result<()> {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&args.url)
+        .await?;
+
+    let result = solve_pair_with_pool(&pool, args).await;
+    pool.close().await;
+    result
+}
+
+/// Break out of solve_pair that runs on an already connected pool
+async fn solve_pair_with_pool(pool: &Pool<Postgres>, args: SolvePairArgs) -> Result<()> {
+    let date_cutoff = date_cutoff(args.expiry);
+    let source = find_station(pool, &args.src_station, args.src_system.as_deref()).await?;
+    let destination = find_station(pool, &args.dst_station, args.dst_system.as_deref()).await?;
+    let source_commodities = source.get_commodities(pool, &date_cutoff).await?;
+    let destination_commodities = destination.get_commodities(pool, &date_cutoff).await?;
+    let source = StationMarket::new(source, source_commodities);
+    let destination = StationMarket::new(destination, destination_commodities);
+
+    let params = SolveParams {
+        capacity: args.capacity,
+        capital: args.capital,
+        min_unit_profit: args.min_unit_profit,
+        min_supply: args.min_supply,
+        estimated_sell_prices: None,
+        min_profit: 0,
+        min_roi: None,
+        only_commodities: None,
+        solver: args.solver,
+    };
+    if args.explain {
+        println!("{}", "🔍 Model:".bold().fg::<Green>());
+        // the explanation ends in a newline, which leaves a gap before the trade
+        println!("{}", explain_knapsack(&source, &destination, &params)?);
+    }
+
+    match solve_knapsack(&source, &destination, &params)? {
+        Some(trade) => {
+            let distance = station_distance(pool, &trade.source, &trade.destination).await;
+            println!("{}", trade.dump_coloured(pool, distance).await)
+        }
+        None => println!("No profitable trade could be found between these stations"),
+    }
+    Ok(())
 }
 
 /// Finds cheapest commodities in the database
-pub async fn find_cheapest(
+pub async fn find_cheapest(args: FindCheapestArgs) -> Result<()> {
+    if args.format != OutputFormat::Text {
+        QUIET.store(true, Ordering::Relaxed);
+    }
+    status!("Setting up PostgreSQL pool on {}", args.url.fg::<Orange>());
+    let pool = PgPoolOptions::new()
+        .max_connections(args.db_connections)
+        .connect(&args.url)
+        .await?;
+
+    let result = find_cheapest_with_pool(&pool, args).await;
+    pool.close().await;
+    result
+}
+
+/// Break out of find_cheapest that runs the search on an already connected pool
+async fn find_cheapest_with_pool(pool: &Pool<Postgres>, args: FindCheapestArgs) -> Result<()> {
+    let FindCheapestArgs {
+        url: _,
+        landing_pad,
+        name,
+        max_age,
+        min_quantity,
+        limit,
+        format,
+        output,
+        db_connections: _,
+    } = args;
+
+    let date_cutoff = (Utc::now() - TimeDelta::days(max_age.into())).naive_utc();
+
+    status!("Searching for the cheapest {}", name.fg::<Orange>());
+    // only the latest listing of each market is considered, and fleet carriers are excluded using
+    // the same pattern as compute_single (Postgres regexes accept it as is)
+    let listings = sqlx::query_as!(
+        CheapestListing,
+        r#"
+            WITH latest AS (
+                SELECT DISTINCT ON (l.market_id) l.market_id, l.buy_price, l.stock, l.listed_at
+                    FROM listings l
+                WHERE LOWER(l.name) = LOWER($1) AND l.listed_at >= $2
+                ORDER BY l.market_id, l.listed_at DESC
+            )
+            SELECT l.buy_price, l.stock, l.listed_at, s.name AS station_name, y.name AS system_name
+                FROM latest l
+            INNER JOIN stations s ON s.market_id = l.market_id
+            INNER JOIN systems y ON y.id = s.system_id
+                WHERE l.buy_price > 0 AND l.stock >= $3 AND s.landing_pad LIKE ANY($4)
+                AND s.name !~ $5
+            ORDER BY l.buy_price ASC
+            LIMIT $6;
+        "#,
+        name,
+        date_cutoff,
+        i32::try_from(min_quantity)?,
+        &landing_pad_patterns(landing_pad),
+        FLEET_CARRIER_REGEX.as_str(),
+        limit,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut out = report_writer(output.as_deref())?;
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &listings)?;
+            writeln!(out)?;
+            return Ok(());
+        }
+        OutputFormat::Csv => return write_listings_csv(out, &listings),
+        OutputFormat::Text => {}
+    }
+
+    if listings.is_empty() {
+        writeln!(
+            out,
+            "No stations with a {:?} landing pad sell at least {} {} (maybe adjust --max-age?)",
+            landing_pad,
+            min_quantity,
+            name.fg::<Orange>()
+        )?;
+        return Ok(());
+    }
+
+    writeln!(out, "{}", "💰 Cheapest listings:".bold().fg::<Green>())?;
+    for (i, listing) in listings.iter().enumerate() {
+        let age = chrono_humanize::HumanTime::from(listing.listed_at - Utc::now().naive_utc());
+        writeln!(
+            out,
+            "{}. {} ({}) for {} CR, {} in stock (updated {})",
+            i + 1,
+            listing.station_name.fg::<Orange>(),
+            listing.system_name.fg::<Orange>(),
+            listing.buy_price.separate_with_commas().fg::<Red>(),
+            listing.stock.separate_with_commas().fg::<Green>(),
+            age.fg::<DarkOrange>()
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Finds the stations within range of the source system that pay the most for a commodity, which
+/// is the reverse of `find_cheapest`
+pub async fn find_best_sell(args: FindBestSellArgs) -> Result<()> {
+    println!("Setting up PostgreSQL pool on {}", args.url.fg::<Orange>());
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&args.url)
+        .await?;
+
+    let result = find_best_sell_with_pool(&pool, args).await;
+    pool.close().await;
+    result
+}
+
+/// Break out of find_best_sell that runs the search on an already connected pool
+async fn find_best_sell_with_pool(pool: &Pool<Postgres>, args: FindBestSellArgs) -> Result<()> {
+    let FindBestSellArgs {
+        url: _,
+        commodity,
+        src,
+        max_dst,
+        landing_pad,
+        min_demand,
+        max_age,
+        limit,
+    } = args;
+
+    let date_cutoff = (Utc::now() - TimeDelta::days(max_age.into())).naive_utc();
+
+    println!(
+        "Finding systems within {} LY of {}",
+        max_dst.fg::<Orange>(),
+        src.fg::<Orange>()
+    );
+    let source_system = get_system_by_name(pool, &src).await?;
+    let systems: HashMap<i64, System> =
+        get_all_systems_in_range(pool, &source_system, max_dst.into())
+            .await?
+            .into_iter()
+            .map(|system| (system.id, system))
+            .collect();
+    let system_ids: Vec<i64> = systems.keys().copied().collect();
+
+    println!(
+        "Searching for the best price for {}",
+        commodity.fg::<Orange>()
+    );
+    // like find_cheapest, only the latest listing of each market is considered. the markets are
+    // narrowed down to those in range first, so that the latest listing isn't found for every
+    // market in the galaxy
+    let listings = sqlx::query!(
+        r#"
+            WITH latest AS (
+                SELECT DISTINCT ON (l.market_id) l.market_id, l.sell_price, l.demand, l.listed_at
+                    FROM listings l
+                WHERE LOWER(l.name) = LOWER($1) AND l.listed_at >= $2
+                AND l.market_id IN (SELECT market_id FROM stations WHERE system_id = ANY($3))
+                ORDER BY l.market_id, l.listed_at DESC
+            )
+            SELECT l.sell_price, l.demand, l.listed_at, s.name AS station_name,
+                y.id AS system_id, y.name AS system_name
+                FROM latest l
+            INNER JOIN stations s ON s.market_id = l.market_id
+            INNER JOIN systems y ON y.id = s.system_id
+                WHERE l.sell_price > 0 AND l.demand >= $4 AND s.system_id = ANY($3)
+                AND s.landing_pad LIKE ANY($5) AND s.name !~ $6
+            ORDER BY l.sell_price DESC
+            LIMIT $7;
+        "#,
+        commodity,
+        date_cutoff,
+        &system_ids,
+        i32::try_from(min_demand)?,
+        &landing_pad_patterns(landing_pad),
+        FLEET_CARRIER_REGEX.as_str(),
+        limit,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    if listings.is_empty() {
+        println!(
+            "No stations with a {:?} landing pad within {max_dst} LY of {src} buy at least {} {} \
+            (maybe adjust --max-age?)",
+            landing_pad,
+            min_demand,
+            commodity.fg::<Orange>()
+        );
+        return Ok(());
+    }
+
+    println!("{}", "💰 Best places to sell:".bold().fg::<Green>());
+    for (i, listing) in listings.iter().enumerate() {
+        let age = chrono_humanize::HumanTime::from(listing.listed_at - Utc::now().naive_utc());
+        let distance = systems
+            .get(&listing.system_id)
+            .map(|system| source_system.coords.dst(&system.coords))
+            .unwrap_or_default();
+        println!(
+            "{}. {} ({}, {:.1} LY) for {} CR, {} in demand (updated {})",
+            i + 1,
+            listing.station_name.fg::<Orange>(),
+            listing.system_name.fg::<Orange>(),
+            distance,
+            listing.sell_price.separate_with_commas().fg::<Green>(),
+            listing.demand.separate_with_commas().fg::<Green>(),
+            age.fg::<DarkOrange>()
+        );
+    }
+
+    Ok(())
+}
+
+/// Lists the commodity listings whose buy or sell price deviates furthest from the galactic mean
+/// price, which is a good indicator of bad data. Only the latest listing of each commodity at each
+/// market is considered, since older ones have been superseded and no longer affect any route.
+pub async fn find_outliers(
     url: String,
-    landing_pad: LandingPad,
-    name: String,
-    max_age: u32,
-    min_quantity: u32,
+    ratio: f64,
+    max_age: Option<u32>,
+    limit: i64,
 ) -> Result<()> {
+    println!("Setting up PostgreSQL pool on {}", url.fg::<Orange>());
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&url)
+        .await?;
+
+    let date_cutoff = date_cutoff(max_age);
+
+    println!("Scanning listings for price outliers");
+    // zero prices mean the commodity isn't bought or sold, so those aren't considered deviations
+    let outliers = sqlx::query!(
+        r#"
+            WITH latest AS (
+                SELECT DISTINCT ON (l.market_id, l.name) l.*
+                    FROM listings l
+                WHERE l.listed_at >= $1
+                ORDER BY l.market_id, l.name, l.listed_at DESC
+            ),
+            deviations AS (
+                SELECT l.*,
+                    GREATEST(
+                        CASE WHEN l.buy_price > 0 THEN ABS(l.buy_price - l.mean_price) ELSE 0 END,
+                        CASE WHEN l.sell_price > 0 THEN ABS(l.sell_price - l.mean_price) ELSE 0 END
+                    )::float8 / l.mean_price AS deviation
+                FROM latest l
+                WHERE l.mean_price > 0
+            )
+            SELECT d.name, d.buy_price, d.sell_price, d.mean_price, d.listed_at,
+                d.deviation AS "deviation!", s.name AS station_name, y.name AS system_name
+                FROM deviations d
+            INNER JOIN stations s ON s.market_id = d.market_id
+            INNER JOIN systems y ON y.id = s.system_id
+                WHERE d.deviation >= $2
+            ORDER BY d.deviation DESC
+            LIMIT $3;
+        "#,
+        date_cutoff,
+        ratio,
+        limit,
+    )
+    .fetch_all(&pool)
+    .await?;
+    pool.close().await;
+
+    println!("{}", "🔍 Largest price outliers:".bold().fg::<Green>());
+    for (i, outlier) in outliers.iter().enumerate() {
+        let age = chrono_humanize::HumanTime::from(outlier.listed_at - Utc::now().naive_utc());
+        println!(
+            "{}. {} at {} in {}: buy {} / sell {} CR vs mean {} CR ({}% off, updated {})",
+            i + 1,
+            outlier.name.bold(),
+            outlier.station_name.fg::<Orange>(),
+            outlier.system_name.fg::<Orange>(),
+            outlier.buy_price.separate_with_commas().fg::<Red>(),
+            outlier.sell_price.separate_with_commas().fg::<Green>(),
+            outlier.mean_price.separate_with_commas(),
+            (outlier.deviation * 100.0).round().separate_with_commas(),
+            age.fg::<DarkOrange>()
+        );
+    }
+
+    Ok(())
+}
+
+/// Lists the markets within `max_dst` LY of the source system whose latest listing is the oldest,
+/// so that they can be visited to refresh their data. Fleet carriers are skipped, since they don't
+/// stay put long enough for this to be useful.
+pub async fn find_stale_markets(url: String, src: String, max_dst: f32, limit: i64) -> Result<()> {
+    println!("Setting up PostgreSQL pool on {}", url.fg::<Orange>());
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&url)
+        .await?;
+
+    println!(
+        "Finding systems within {} LY of {}",
+        max_dst.fg::<Orange>(),
+        src.fg::<Orange>()
+    );
+    let source_system = get_system_by_name(&pool, &src).await?;
+    let system_ids: Vec<i64> = get_all_systems_in_range(&pool, &source_system, max_dst.into())
+        .await?
+        .iter()
+        .map(|system| system.id)
+        .collect();
+
+    let markets = sqlx::query!(
+        r#"
+            SELECT s.name AS station_name, y.name AS system_name,
+                MAX(l.listed_at) AS "last_updated!"
+                FROM stations s
+            INNER JOIN systems y ON y.id = s.system_id
+            INNER JOIN listings l ON l.market_id = s.market_id
+                WHERE s.system_id = ANY($1) AND s.name !~ $2
+            GROUP BY s.id, s.name, y.name
+            ORDER BY MAX(l.listed_at) ASC
+            LIMIT $3;
+        "#,
+        &system_ids,
+        FLEET_CARRIER_REGEX.as_str(),
+        limit,
+    )
+    .fetch_all(&pool)
+    .await?;
+    pool.close().await;
+
+    if markets.is_empty() {
+        println!("No markets were found within {max_dst} LY of {src}");
+        return Ok(());
+    }
+
+    println!("{}", "🕸️ Stalest markets:".bold().fg::<Green>());
+    for (i, market) in markets.iter().enumerate() {
+        let age = chrono_humanize::HumanTime::from(market.last_updated - Utc::now().naive_utc());
+        println!(
+            "{}. {} in {} (updated {})",
+            i + 1,
+            market.station_name.fg::<Orange>(),
+            market.system_name.fg::<Orange>(),
+            age.fg::<DarkOrange>()
+        );
+    }
+
+    Ok(())
+}
+
+/// Tables that kural reads from, and the columns of each that its queries rely on
+const REQUIRED_COLUMNS: [(&str, &[&str]); 3] = [
+    (
+        "stations",
+        &[
+            "id",
+            "name",
+            "distance_to_arrival",
+            "market_id",
+            "system_id",
+            "station_type",
+            "landing_pad",
+        ],
+    ),
+    ("systems", &["id", "name", "date", "coords", "needs_permit"]),
+    (
+        "listings",
+        &[
+            "market_id",
+            "name",
+            "mean_price",
+            "buy_price",
+            "sell_price",
+            "demand",
+            "demand_bracket",
+            "stock",
+            "stock_bracket",
+            "listed_at",
+        ],
+    ),
+];
+
+/// Checks that the database at `url` can be connected to, has every table and column that kural
+/// queries, and has PostGIS installed. Prints the outcome of each check, along with some row counts
+/// if everything is in order, and fails if any check did.
+pub async fn doctor(url: String) -> Result<()> {
+    println!("Connecting to PostgreSQL on {}", url.fg::<Orange>());
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&url)
+        .await
+        .map_err(|err| eyre!("Could not connect to the database: {err}"))?;
+    println!("{} Connected", "✅".fg::<Green>());
+
+    let mut failures = 0;
+    for (table, required) in REQUIRED_COLUMNS {
+        let columns: HashSet<String> = sqlx::query_scalar!(
+            r#"
+                SELECT column_name::text AS "column_name!"
+                    FROM information_schema.columns
+                WHERE table_name::text = $1;
+            "#,
+            table
+        )
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .collect();
+
+        let missing: Vec<&str> = required
+            .iter()
+            .copied()
+            .filter(|column| !columns.contains(*column))
+            .collect();
+        if columns.is_empty() {
+            failures += 1;
+            println!("{} Table '{table}' is missing", "❌".fg::<Red>());
+        } else if !missing.is_empty() {
+            failures += 1;
+            println!(
+                "{} Table '{table}' is missing the columns {}",
+                "❌".fg::<Red>(),
+                missing.join(", ").fg::<Orange>()
+            );
+        } else {
+            println!(
+                "{} Table '{table}' has all required columns",
+                "✅".fg::<Green>()
+            );
+        }
+    }
+
+    // ST_3DDWithin and friends are used to find systems in range
+    let postgis = sqlx::query_scalar!(
+        r#"
+            SELECT extversion AS version FROM pg_extension WHERE extname = 'postgis';
+        "#
+    )
+    .fetch_optional(&pool)
+    .await?;
+    match postgis {
+        Some(version) => println!(
+            "{} PostGIS {} is installed",
+            "✅".fg::<Green>(),
+            version.fg::<Orange>()
+        ),
+        None => {
+            failures += 1;
+            println!(
+                "{} PostGIS is not installed, run {}",
+                "❌".fg::<Red>(),
+                "CREATE EXTENSION postgis;".fg::<Orange>()
+            );
+        }
+    }
+
+    // the counts query touches every required table, so it can only run once they're all there
+    if failures > 0 {
+        pool.close().await;
+        return Err(eyre!(
+            "{failures} checks failed, is this an EDTear database that has been fully migrated?"
+        ));
+    }
+
+    let counts = sqlx::query!(
+        r#"
+            SELECT
+                (SELECT COUNT(*) FROM stations) AS "stations!",
+                (SELECT COUNT(*) FROM systems) AS "systems!",
+                (SELECT COUNT(*) FROM listings) AS "listings!",
+                (SELECT MAX(listed_at) FROM listings) AS newest_listing;
+        "#
+    )
+    .fetch_one(&pool)
+    .await?;
+    pool.close().await;
+
+    println!("{}", "🩺 Database contents:".bold().fg::<Green>());
+    println!(
+        "    {} stations, {} systems, {} listings",
+        counts.stations.separate_with_commas().fg::<Orange>(),
+        counts.systems.separate_with_commas().fg::<Orange>(),
+        counts.listings.separate_with_commas().fg::<Orange>()
+    );
+    match counts.newest_listing {
+        Some(newest) => println!(
+            "    newest listing is from {}",
+            chrono_humanize::HumanTime::from(newest - Utc::now().naive_utc()).fg::<DarkOrange>()
+        ),
+        None => println!("    {}", "there are no listings yet".fg::<DarkOrange>()),
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_market_ages() {
+        assert_eq!(data_age_bucket(TimeDelta::minutes(5)), 0);
+        assert_eq!(data_age_bucket(TimeDelta::hours(1)), 1);
+        assert_eq!(data_age_bucket(TimeDelta::days(3)), 2);
+        assert_eq!(data_age_bucket(TimeDelta::days(29)), 3);
+        assert_eq!(data_age_bucket(TimeDelta::days(365)), 4);
+    }
+
+    #[test]
+    fn detects_fleet_carriers() {
+        for name in ["K7Q-BQL", "X9Z-01A", "V2W-T5N", "123-456"] {
+            assert!(is_fleet_carrier(name), "{name} should be a fleet carrier");
+        }
+    }
+
+    #[test]
+    fn ignores_normal_stations() {
+        for name in [
+            "Abc-Def Orbital",
+            "Jameson Memorial",
+            "Hutton Orbital",
+            "Ray Gateway",
+            "Dav's Hope",
+            "Xyz-123 Port",
+            "Port ABC-DEF",
+            "abc-def",
+            "ABCD-EFG",
+        ] {
+            assert!(
+                !is_fleet_carrier(name),
+                "{name} should not be a fleet carrier"
+            );
+        }
+    }
+
+    fn station(id: i64) -> Station {
+        Station {
+            id,
+            name: format!("Station {id}"),
+            distance_to_arrival: None,
+            market_id: Some(id),
+            system_id: Some(id),
+            system_name: Some(format!("System {id}")),
+            station_type: None,
+        }
+    }
+
+    fn listing(name: &str, stock: i32, demand: i32) -> Commodity {
+        Commodity {
+            name: name.to_string(),
+            stock,
+            demand,
+            ..Commodity::default()
+        }
+    }
+
+    #[test]
+    fn trade_index_matches_sells_to_buys() {
+        let stations = [station(1), station(2), station(3), station(4)];
+        let all_commodities = DashMap::new();
+        all_commodities.insert(1, vec![listing("Gold", 100, 0), listing("Silver", 0, 100)]);
+        all_commodities.insert(2, vec![listing("Gold", 0, 100)]);
+        all_commodities.insert(3, vec![listing("Silver", 100, 0), listing("Tea", 0, 0)]);
+        let index = TradeIndex::new(&stations, &all_commodities);
+
+        assert!(index.can_trade(1, 2));
+        assert!(index.can_trade(3, 1));
+        // a commodity that's listed but has no demand isn't bought
+        assert!(!index.can_trade(1, 3));
+        assert!(!index.can_trade(2, 1));
+        // nothing is known about a station without a market
+        assert!(!index.can_trade(1, 4));
+        assert!(!index.can_trade(4, 2));
+    }
+
+    fn trade(source: i64, destination: i64, profit: f64) -> TradeSolution {
+        TradeSolution::new(
+            station(source),
+            station(destination),
+            Vec::new(),
+            profit,
+            0.0,
+            0,
+        )
+    }
+
+    /// The (source, destination) station IDs of each leg of a loop
+    fn route(legs: &[&TradeSolution]) -> Vec<(i64, i64)> {
+        legs.iter()
+            .map(|leg| (leg.source.id, leg.destination.id))
+            .collect()
+    }
+
+    #[test]
+    fn finds_most_profitable_loop_within_max_legs() {
+        let solutions = [
+            trade(1, 2, 10.0),
+            trade(2, 3, 10.0),
+            trade(3, 1, 10.0),
+            trade(2, 4, 50.0),
+            trade(4, 3, 50.0),
+        ];
+
+        let legs = find_best_loop(&solutions, 4, None).expect("a loop should be found");
+        assert_eq!(route(&legs), vec![(1, 2), (2, 4), (4, 3), (3, 1)]);
+        // the detour through 4 needs a fourth leg
+        let legs = find_best_loop(&solutions, 3, None).expect("a loop should be found");
+        assert_eq!(route(&legs), vec![(1, 2), (2, 3), (3, 1)]);
+    }
+
+    #[test]
+    fn loops_start_from_their_lowest_station() {
+        let solutions = [trade(3, 1, 10.0), trade(2, 3, 10.0), trade(1, 2, 10.0)];
+
+        let legs = find_best_loop(&solutions, 3, None).expect("a loop should be found");
+        assert_eq!(route(&legs), vec![(1, 2), (2, 3), (3, 1)]);
+    }
+
+    #[test]
+    fn loops_need_a_closing_leg() {
+        let solutions = [trade(1, 2, 10.0), trade(2, 3, 10.0)];
+
+        assert!(find_best_loop(&solutions, 5, None).is_none());
+    }
+
+    #[test]
+    fn round_trips_are_not_loops() {
+        let solutions = [trade(1, 2, 10.0), trade(2, 1, 10.0)];
+
+        assert!(find_best_loop(&solutions, 5, None).is_none());
+    }
+
+    #[test]
+    fn unprofitable_trades_are_not_loop_legs() {
+        let solutions = [trade(1, 2, 10.0), trade(2, 3, 10.0), trade(3, 1, 0.0)];
+
+        assert!(find_best_loop(&solutions, 3, None).is_none());
+    }
+
+    #[test]
+    fn loops_through_a_station_start_from_it() {
+        let solutions = [
+            trade(1, 2, 10.0),
+            trade(2, 3, 10.0),
+            trade(3, 1, 10.0),
+            trade(1, 4, 20.0),
+            trade(4, 5, 20.0),
+            trade(5, 1, 20.0),
+        ];
+
+        let through = HashSet::from([3]);
+        let legs = find_best_loop(&solutions, 3, Some(&through)).expect("a loop should be found");
+        assert_eq!(route(&legs), vec![(3, 1), (1, 2), (2, 3)]);
+        let through = HashSet::from([6]);
+        assert!(find_best_loop(&solutions, 3, Some(&through)).is_none());
+    }
+
+    #[test]
+    fn sample_size_is_floored() {
+        // 0.01 * 40 rounds to 0
+        assert_eq!(compute_sample_size(0.01, 40), MIN_SAMPLE_SIZE);
+        assert_eq!(compute_sample_size(0.0, 1000), MIN_SAMPLE_SIZE);
+    }
+
+    #[test]
+    fn sample_size_floor_is_capped_by_available() {
+        assert_eq!(compute_sample_size(0.1, 4), 4);
+        assert_eq!(compute_sample_size(0.5, 0), 0);
+    }
+
+    #[test]
+    fn sample_size_is_capped_by_available() {
+        assert_eq!(compute_sample_size(2.0, 50), 50);
+        assert_eq!(compute_sample_size(1.0, 50), 50);
+        assert_eq!(compute_sample_size(0.5, 50), 25);
+    }
+}
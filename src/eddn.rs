@@ -0,0 +1,231 @@
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use flate2::read::ZlibDecoder;
+use log::{debug, warn};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use sqlx::{Pool, Postgres};
+use std::io::Read;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+use zeromq::{Socket, SocketRecv, SubSocket};
+
+/// Public EDDN relay, which republishes every message uploaded to EDDN
+pub const EDDN_RELAY: &str = "tcp://eddn.edcd.io:9500";
+
+/// Schema of the EDDN messages carrying a station's full market
+const COMMODITY_SCHEMA: &str = "https://eddn.edcd.io/schemas/commodity/3";
+
+/// Envelope that every EDDN message is wrapped in
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    #[serde(rename = "$schemaRef")]
+    schema_ref: String,
+    message: serde_json::Value,
+}
+
+/// Body of a commodity v3 message
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MarketMessage {
+    market_id: i64,
+    timestamp: DateTime<Utc>,
+    commodities: Vec<MarketCommodity>,
+}
+
+/// A single commodity in a commodity v3 message
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MarketCommodity {
+    name: String,
+    mean_price: i32,
+    buy_price: i32,
+    sell_price: i32,
+    demand: i32,
+    #[serde(deserialize_with = "bracket")]
+    demand_bracket: i32,
+    stock: i32,
+    #[serde(deserialize_with = "bracket")]
+    stock_bracket: i32,
+}
+
+/// Reads a demand or stock bracket, which the game sends as an empty string rather than 0 when
+/// there is none
+fn bracket<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Bracket {
+        Level(i32),
+        Empty(String),
+    }
+    match Bracket::deserialize(deserializer)? {
+        Bracket::Level(level) => Ok(level),
+        Bracket::Empty(empty) if empty.is_empty() => Ok(0),
+        Bracket::Empty(other) => Err(D::Error::custom(format!("invalid bracket '{other}'"))),
+    }
+}
+
+/// What was saved while listening to EDDN
+#[derive(Debug, Default)]
+pub struct IngestStats {
+    /// Number of market updates saved
+    pub markets: usize,
+    /// Number of commodity listings saved across all markets
+    pub listings: usize,
+    /// Number of messages that couldn't be decoded or saved, and were skipped
+    pub skipped: usize,
+}
+
+/// Decompresses and parses a raw EDDN message, returning None if it isn't a commodity message
+fn decode(frame: &[u8]) -> Result<Option<MarketMessage>> {
+    let mut json = String::new();
+    ZlibDecoder::new(frame).read_to_string(&mut json)?;
+    let envelope: Envelope = serde_json::from_str(&json)?;
+    if envelope.schema_ref != COMMODITY_SCHEMA {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_value(envelope.message)?))
+}
+
+/// Saves a market update as new listings, which then supersede the older listings of the same
+/// market when commodities are fetched
+async fn save(pool: &Pool<Postgres>, market: &MarketMessage) -> Result<()> {
+    let column = |get: fn(&MarketCommodity) -> i32| -> Vec<i32> {
+        market.commodities.iter().map(get).collect()
+    };
+    let names: Vec<String> = market
+        .commodities
+        .iter()
+        .map(|commodity| commodity.name.clone())
+        .collect();
+
+    sqlx::query!(
+        r#"
+            INSERT INTO listings (market_id, name, mean_price, buy_price, sell_price, demand,
+                                  demand_bracket, stock, stock_bracket, listed_at)
+                SELECT $1, u.*, $10
+                FROM UNNEST($2::text[], $3::int4[], $4::int4[], $5::int4[], $6::int4[], $7::int4[],
+                            $8::int4[], $9::int4[]) AS u;
+        "#,
+        market.market_id,
+        &names,
+        &column(|it| it.mean_price),
+        &column(|it| it.buy_price),
+        &column(|it| it.sell_price),
+        &column(|it| it.demand),
+        &column(|it| it.demand_bracket),
+        &column(|it| it.stock),
+        &column(|it| it.stock_bracket),
+        market.timestamp.naive_utc(),
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Subscribes to the EDDN relay for the given duration, saving every market update received into
+/// the listings table
+pub async fn ingest(pool: &Pool<Postgres>, relay: &str, duration: Duration) -> Result<IngestStats> {
+    let mut socket = SubSocket::new();
+    socket
+        .connect(relay)
+        .await
+        .map_err(|err| eyre!("Could not connect to EDDN relay {relay}: {err}"))?;
+    socket.subscribe("").await?;
+
+    let deadline = Instant::now() + duration;
+    let mut stats = IngestStats::default();
+    // the relay never stops sending, so listening only ends once the time is up
+    while let Ok(received) = timeout_at(deadline, socket.recv()).await {
+        let message = received?;
+        let Some(frame) = message.get(0) else {
+            continue;
+        };
+        match decode(frame) {
+            Ok(Some(market)) => match save(pool, &market).await {
+                Ok(()) => {
+                    stats.markets += 1;
+                    stats.listings += market.commodities.len();
+                }
+                // one bad market update shouldn't throw away the rest, or the run waiting on them
+                Err(err) => {
+                    warn!(
+                        "Skipping EDDN update for market {} that could not be saved: {err}",
+                        market.market_id
+                    );
+                    stats.skipped += 1;
+                }
+            },
+            Ok(None) => {}
+            Err(err) => {
+                debug!("Skipping undecodable EDDN message: {err}");
+                stats.skipped += 1;
+            }
+        }
+    }
+
+    if stats.skipped > 0 {
+        warn!(
+            "{} EDDN messages could not be decoded or saved, and were skipped",
+            stats.skipped
+        );
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn compress(json: &str) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(json.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decodes_commodity_message_with_empty_brackets() {
+        let frame = compress(
+            r#"{
+                "$schemaRef": "https://eddn.edcd.io/schemas/commodity/3",
+                "header": {"uploaderID": "test", "softwareName": "test", "softwareVersion": "1"},
+                "message": {
+                    "systemName": "Sol",
+                    "stationName": "Abraham Lincoln",
+                    "marketId": 128016640,
+                    "timestamp": "2025-01-02T03:04:05Z",
+                    "commodities": [{
+                        "name": "gold",
+                        "meanPrice": 47000,
+                        "buyPrice": 45000,
+                        "stock": 120,
+                        "stockBracket": 2,
+                        "sellPrice": 44000,
+                        "demand": 0,
+                        "demandBracket": ""
+                    }]
+                }
+            }"#,
+        );
+
+        let market = decode(&frame)
+            .unwrap()
+            .expect("should be a commodity message");
+        assert_eq!(market.market_id, 128016640);
+        assert_eq!(market.commodities.len(), 1);
+        assert_eq!(market.commodities[0].name, "gold");
+        assert_eq!(market.commodities[0].stock_bracket, 2);
+        assert_eq!(market.commodities[0].demand_bracket, 0);
+    }
+
+    #[test]
+    fn ignores_other_schemas() {
+        let frame =
+            compress(r#"{"$schemaRef": "https://eddn.edcd.io/schemas/journal/1", "message": {}}"#);
+        assert!(decode(&frame).unwrap().is_none());
+    }
+}
@@ -0,0 +1,39 @@
+use good_lp::ResolutionError;
+use thiserror::Error;
+
+/// Errors that can occur while fetching or solving a single station or trade. These are expected to
+/// be logged and skipped, so that one bad station doesn't take the whole computation down with it.
+#[derive(Debug, Error)]
+pub enum KuralError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("station {0} has no market")]
+    MissingMarket(String),
+
+    #[error("commodity {commodity} is not listed at {station}")]
+    MissingCommodity { station: String, commodity: String },
+
+    #[error("could not solve {from} -> {to}: {reason}")]
+    Solver {
+        from: String,
+        to: String,
+        reason: ResolutionError,
+    },
+}
+
+impl KuralError {
+    /// Returns true if the error is likely to go away by itself, so that the operation is worth
+    /// retrying. That's a dropped connection, a pool that's too busy, a Postgres connection
+    /// exception (SQLSTATE class 08), the server shutting down (57P01) or a serialization failure
+    /// (40001). Anything else, like a malformed query, will just fail again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            KuralError::Database(sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut) => true,
+            KuralError::Database(sqlx::Error::Database(err)) => err
+                .code()
+                .is_some_and(|code| code.starts_with("08") || code == "57P01" || code == "40001"),
+            _ => false,
+        }
+    }
+}
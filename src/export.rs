@@ -0,0 +1,111 @@
+use crate::types::{CheapestListing, Station, TradeSolution};
+use color_eyre::Result;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Streams the profit of every evaluated station pair to a CSV file, as a sparse matrix keyed by
+/// market ID. Rows are written as they are computed, so the full matrix never has to be held in
+/// memory.
+pub struct MatrixExporter {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl MatrixExporter {
+    /// Creates the CSV file at the given path and writes its header
+    pub fn new(path: &Path) -> std::io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(
+            writer,
+            "source_market_id,dest_market_id,profit,cost,overlap_count"
+        )?;
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Records an evaluated pair. Pairs which could not be solved are recorded with zero profit
+    /// and cost.
+    pub fn record(
+        &self,
+        source: &Station,
+        destination: &Station,
+        solution: Option<&TradeSolution>,
+        overlap: usize,
+    ) -> std::io::Result<()> {
+        let (profit, cost) = solution.map_or((0.0, 0.0), |sol| (sol.profit, sol.cost));
+        let mut writer = self.writer.lock().unwrap();
+        writeln!(
+            writer,
+            "{},{},{},{},{}",
+            source.market_id.unwrap_or_default(),
+            destination.market_id.unwrap_or_default(),
+            profit.round(),
+            cost.round(),
+            overlap
+        )
+    }
+
+    /// Flushes any buffered rows to disk
+    pub fn finish(&self) -> std::io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+/// Writes trades to the writer as a JSON array
+pub fn write_json<W: Write>(writer: W, trades: &[TradeSolution]) -> Result<()> {
+    serde_json::to_writer_pretty(writer, trades)?;
+    Ok(())
+}
+
+/// Writes trades to the writer as CSV, one row per trade. The commodities to buy are flattened into
+/// a single semicolon separated column.
+pub fn write_csv<W: Write>(writer: W, trades: &[TradeSolution]) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record([
+        "source_station",
+        "source_system",
+        "dest_station",
+        "dest_system",
+        "profit",
+        "cost",
+        "roi",
+        "total_units",
+        "buy",
+    ])?;
+
+    for trade in trades {
+        let buy = trade
+            .buy
+            .iter()
+            .filter(|order| order.count > 0)
+            .map(|order| format!("{}x {}", order.count, order.commodity_name))
+            .collect::<Vec<String>>()
+            .join(";");
+        writer.write_record([
+            trade.source.name.as_str(),
+            trade.source.system_name.as_deref().unwrap_or_default(),
+            trade.destination.name.as_str(),
+            trade.destination.system_name.as_deref().unwrap_or_default(),
+            &trade.profit.round().to_string(),
+            &trade.cost.round().to_string(),
+            &format!("{:.4}", trade.roi()),
+            &trade.units().to_string(),
+            &buy,
+        ])?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes listings found by `find_cheapest` to the writer as CSV, one row per listing
+pub fn write_listings_csv<W: Write>(writer: W, listings: &[CheapestListing]) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for listing in listings {
+        writer.serialize(listing)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,6 @@
+//! The market types and knapsack solver at the core of kural, kept apart from the CLI so that they
+//! can be benchmarked without a database
+
+pub mod error;
+pub mod solve;
+pub mod types;
@@ -1,50 +1,292 @@
-use crate::types::{Order, StationMarket, TradeSolution};
+use crate::error::KuralError;
+use crate::types::{Commodity, Order, StationMarket, TradeSolution};
 use good_lp::{constraint, highs, variable, Expression, ProblemVariables, Variable};
-use good_lp::{Solution, SolverModel};
-use log::{debug, error};
-use std::collections::BTreeMap;
+use good_lp::{ResolutionError, Solution, SolverModel};
+use log::{debug, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
 
-/// Solves an instance of the bounded knapsack problem using linear programming. Returns Some if a
-/// solution could be computed, otherwise None.
-pub fn solve_knapsack(
-    source: StationMarket,
-    destination: StationMarket,
-    capacity: u32,
-    capital: u64,
-) -> Option<TradeSolution> {
-    // FIXME we *need* to stop unwrappping shit in this routine
+#[derive(Debug, Clone, Copy, clap::ValueEnum, PartialEq, Eq)]
+pub enum Solver {
+    /// The HiGHS integer linear programming solver, which always finds the most profitable cargo
+    Highs,
+    /// A pure Rust heuristic that buys the commodities making the most profit per credit first.
+    /// Much faster than HiGHS, but may be slightly less profitable. It's also used for any trade
+    /// that HiGHS fails to solve.
+    Greedy,
+}
 
-    // first, compute profit for all commodities from dest to source per unit carried
-    // this maps a commodity name to an expected profit
-    // we use a btreemap here for deterministic iteration order
-    let mut profit: BTreeMap<String, i32> = BTreeMap::new();
-    let all_dest_commodity_names: Vec<String> = destination
+/// Parameters that constrain a single knapsack solve
+#[derive(Debug, Clone, Copy)]
+pub struct SolveParams<'a> {
+    /// Ship cargo capacity
+    pub capacity: u32,
+    /// Capital available to purchase commodities
+    pub capital: u64,
+    /// Commodities making less than this much profit per unit are not considered
+    pub min_unit_profit: u32,
+    /// Commodities that the source has less than this many units of in stock are not considered
+    pub min_supply: u32,
+    /// If set, commodities not listed at the destination are assumed to sell for the galactic
+    /// average price given in this map, rather than being skipped
+    pub estimated_sell_prices: Option<&'a HashMap<String, i32>>,
+    /// Trades making less than this much profit are discarded
+    pub min_profit: u64,
+    /// Trades with a lower ratio of profit to cost than this are discarded. Only applies to trades
+    /// that cost something.
+    pub min_roi: Option<f64>,
+    /// If set, only commodities whose lowercased name is in this set are considered
+    pub only_commodities: Option<&'a HashSet<String>>,
+    /// Solver used to pick the cargo
+    pub solver: Solver,
+}
+
+impl SolveParams<'_> {
+    /// Returns true if the trade meets the minimum profit and ROI thresholds
+    pub fn accepts(&self, trade: &TradeSolution) -> bool {
+        trade.profit >= self.min_profit as f64
+            && self
+                .min_roi
+                .is_none_or(|min_roi| trade.cost <= 0.0 || trade.roi() >= min_roi)
+    }
+}
+
+/// Buy price of the cheapest commodity at the source that sells for more at the destination, or
+/// None if no commodity in stock can be traded at a profit
+pub fn cheapest_profitable_price(
+    source: &StationMarket,
+    destination: &StationMarket,
+) -> Option<i32> {
+    source
+        .commodities
+        .iter()
+        .filter(|commodity| commodity.stock > 0)
+        .filter(|commodity| {
+            destination
+                .get_commodity_ref(&commodity.name)
+                .is_some_and(|dest| dest.sell_price > commodity.buy_price)
+        })
+        .map(|commodity| commodity.buy_price)
+        .min()
+}
+
+/// Computes the profit of filling the hold with only a single commodity, bought at the source and
+/// sold at the destination. Returns None if the commodity isn't traded at both stations.
+pub fn single_commodity_profit(
+    source: &StationMarket,
+    destination: &StationMarket,
+    commodity_name: &str,
+    params: &SolveParams<'_>,
+) -> Option<f64> {
+    let name = commodity_name.to_lowercase();
+    let bought = source
+        .commodities
+        .iter()
+        .find(|commodity| commodity.name.to_lowercase() == name)?;
+    let sold = destination
         .commodities
         .iter()
-        .map(|commodity| commodity.name.clone())
+        .find(|commodity| commodity.name.to_lowercase() == name)?;
+
+    // buy as many as we can fit, afford, and the source has in stock
+    let affordable = if bought.buy_price > 0 {
+        params.capital / bought.buy_price as u64
+    } else {
+        u64::MAX
+    };
+    let count = (params.capacity as u64)
+        .min(affordable)
+        .min(bought.stock.max(0) as u64);
+
+    Some(count as f64 * (sold.sell_price - bought.buy_price) as f64)
+}
+
+/// Solver values within this distance of a whole number are taken to be that number. HiGHS only
+/// solves integer variables to within a small tolerance, so e.g. 100 units may come back as
+/// 99.9999997.
+const INTEGER_TOLERANCE: f64 = 1e-6;
+
+/// A commodity that can be traded at a profit, as offered to the solver
+#[derive(Debug, Clone, Copy)]
+struct Item {
+    unit_profit: i32,
+    buy_price: i32,
+    /// Most units that can be bought
+    max: i32,
+    /// Cargo space taken up by each unit
+    weight: u32,
+}
+
+/// Quantity of a single commodity picked by the solver
+#[derive(Debug, Clone, Copy)]
+struct Pick {
+    /// Number of units, as returned by the solver
+    value: f64,
+    buy_price: i32,
+    unit_profit: i32,
+    weight: u32,
+}
+
+/// Turns the solver's picks into whole unit counts. Values close enough to a whole number are
+/// rounded to it, and anything else is rounded down. Since rounding up may overshoot the cargo
+/// hold or the capital by a unit, the least profitable picks are then cut back until both fit.
+fn round_picks(picks: &[Pick], capacity: u32, capital: u64) -> Vec<u32> {
+    let mut counts: Vec<u32> = picks
+        .iter()
+        .map(|pick| {
+            let nearest = pick.value.round();
+            let count = if (pick.value - nearest).abs() <= INTEGER_TOLERANCE {
+                nearest
+            } else {
+                pick.value.floor()
+            };
+            count.max(0.0) as u32
+        })
         .collect();
 
+    loop {
+        let quantity: u64 = counts
+            .iter()
+            .zip(picks)
+            .map(|(&count, pick)| count as u64 * pick.weight as u64)
+            .sum();
+        // compared as i128, since capital above i64::MAX would wrap around to a negative i64
+        let cost: i128 = counts
+            .iter()
+            .zip(picks)
+            .map(|(&count, pick)| count as i128 * pick.buy_price as i128)
+            .sum();
+        if quantity <= capacity as u64 && cost <= capital as i128 {
+            return counts;
+        }
+
+        // this always finds something, as nothing bought costs nothing and takes up no space
+        let Some(cheapest) = (0..counts.len())
+            .filter(|&i| counts[i] > 0)
+            .min_by_key(|&i| picks[i].unit_profit)
+        else {
+            return counts;
+        };
+        counts[cheapest] -= 1;
+    }
+}
+
+/// Approximates the bounded knapsack problem by buying as much as possible of the commodities that
+/// make the most profit per credit spent, in that order. This needs no native solver, but may end
+/// up slightly less profitable than the optimal cargo, e.g. when the hold rather than the capital
+/// is what runs out.
+fn solve_greedy(items: &[Item], capacity: u32, capital: u64) -> Vec<u32> {
+    // free commodities are infinitely dense, so are always bought first
+    let density = |item: &Item| {
+        if item.buy_price > 0 {
+            item.unit_profit as f64 / item.buy_price as f64
+        } else {
+            f64::INFINITY
+        }
+    };
+    let mut by_density: Vec<usize> = (0..items.len()).collect();
+    by_density.sort_by(|&a, &b| {
+        density(&items[b])
+            .total_cmp(&density(&items[a]))
+            .then(items[b].unit_profit.cmp(&items[a].unit_profit))
+    });
+
+    let mut counts = vec![0; items.len()];
+    let mut space = capacity as u64;
+    let mut money = capital;
+    for i in by_density {
+        let item = items[i];
+        let price = item.buy_price.max(0) as u64;
+        let affordable = money.checked_div(price).unwrap_or(u64::MAX);
+        let fits = space.checked_div(item.weight.into()).unwrap_or(u64::MAX);
+        let count = (item.max.max(0) as u64).min(fits).min(affordable);
+        counts[i] = count as u32;
+        space -= count * item.weight as u64;
+        money -= count * price;
+    }
+    counts
+}
+
+/// Cargo space taken up by a single unit of the commodity. Every commodity currently takes up one
+/// ton, but this is the one place to change if the market data ever carries a tonnage.
+fn weight(_commodity: &Commodity) -> u32 {
+    1
+}
+
+/// Looks up a commodity in the market, or fails if it isn't listed there
+fn require_commodity<'a>(
+    market: &'a StationMarket,
+    name: &str,
+) -> Result<&'a Commodity, KuralError> {
+    market
+        .get_commodity_ref(name)
+        .ok_or_else(|| KuralError::MissingCommodity {
+            station: market.station.name.clone(),
+            commodity: name.to_string(),
+        })
+}
+
+/// Commodities that can be traded at a profit between two stations, as offered to the solver
+struct Candidates {
+    /// Names of the commodities, in the same order as `items`
+    names: Vec<String>,
+    items: Vec<Item>,
+    /// Commodities whose sell price at the destination was estimated
+    estimated: HashSet<String>,
+}
+
+/// Works out which commodities can be traded at a profit from the source to the destination, and
+/// how many units of each could be bought. Returns None if there are none.
+fn candidates(
+    source: &StationMarket,
+    destination: &StationMarket,
+    params: &SolveParams,
+) -> Result<Option<Candidates>, KuralError> {
+    // first, compute profit for all commodities from dest to source per unit carried
+    // this maps a commodity name to an expected profit
+    // we use a btreemap here for deterministic iteration order
+    let mut profit: BTreeMap<String, i32> = BTreeMap::new();
+    // commodities whose sell price in the destination has been estimated
+    let mut estimated: HashSet<String> = HashSet::new();
     for commodity in &source.commodities {
-        // check that this commodity is present in the destination
-        if !all_dest_commodity_names.contains(&commodity.name) {
+        if commodity.stock < params.min_supply as i32 {
+            continue;
+        }
+        if params
+            .only_commodities
+            .is_some_and(|names| !names.contains(&commodity.name.to_lowercase()))
+        {
             continue;
         }
 
-        let dest_commodity = destination.get_commodity(&commodity.name);
-        if dest_commodity.is_none() {
-            // commodity doesn't exist in destination system
+        // check that this commodity is present in the destination, otherwise fall back to the
+        // galactic average sell price if we've been asked to
+        let sell_price = if let Some(listing) = destination.get_commodity_ref(&commodity.name) {
+            listing.sell_price
+        } else if let Some(price) = params
+            .estimated_sell_prices
+            .and_then(|prices| prices.get(&commodity.name))
+        {
+            estimated.insert(commodity.name.clone());
+            *price
+        } else {
+            continue;
+        };
+
+        // commodities that can't be sold at a profit would never be picked, so don't waste
+        // variables on them. also skip commodities that only make a handful of credits per unit,
+        // they just clutter the buy list
+        let unit_profit = sell_price - commodity.buy_price;
+        if unit_profit <= 0 || unit_profit < params.min_unit_profit as i32 {
             continue;
         }
 
-        profit.insert(
-            commodity.name.clone(),
-            dest_commodity.unwrap().sell_price - commodity.buy_price,
-        );
+        profit.insert(commodity.name.clone(), unit_profit);
     }
 
     // no routes available
     if profit.is_empty() {
-        return None;
+        return Ok(None);
     }
 
     // now, model the bounded knapsack problem:
@@ -52,89 +294,503 @@ pub fn solve_knapsack(
     // maximise
     //          sum_(i=1)^n v_i x_i
     // subject to (cargo hold constraint)
-    //          sum_(i=1)^n x_i <= W where x_i in {0, 1, 2, ..., t_i}
+    //          sum_(i=1)^n w_i x_i <= W where x_i in {0, 1, 2, ..., t_i}
     // subject to (capital constraint)
     //          sum_(i=1)^n c_i x_i <= C
     //
     // where:
     //  v_i = profit for the item
     //  x_i = number of copies of item x_i
+    //  w_i = cargo space taken up by one copy of item i
     //  W = cargo hold capacity
     //  t_i = total available quantity for the item
     //  c_i = cost of item i
     //  C = total available capital
 
-    let mut vars = ProblemVariables::new();
-    // n items
-    let n = profit.len();
-    // this represents the number items
-    let mut x: Vec<Variable> = Vec::with_capacity(n);
+    let mut items: Vec<Item> = Vec::with_capacity(profit.len());
+    for (com, unit_profit) in &profit {
+        let bought = require_commodity(source, com)?;
+        // the max is the maximum number of items we can pick up in the source system, and also no
+        // more than the destination can absorb before its price craters. commodities with an
+        // estimated sell price have no known demand, so are only limited by stock.
+        let max = match destination.get_commodity_ref(com) {
+            Some(dest_commodity) if !estimated.contains(com) => {
+                bought.stock.min(dest_commodity.demand)
+            }
+            _ => bought.stock,
+        };
+        items.push(Item {
+            unit_profit: *unit_profit,
+            buy_price: bought.buy_price,
+            max,
+            weight: weight(bought),
+        });
+    }
 
-    for com in profit.keys() {
-        // the max is the maximum number of items we can pick up in the source system
-        let max = source.get_commodity(com).unwrap().stock;
-        x.push(vars.add(variable().min(0).max(max).integer()));
+    Ok(Some(Candidates {
+        names: profit.into_keys().collect(),
+        items,
+        estimated,
+    }))
+}
+
+/// Solves an instance of the bounded knapsack problem using linear programming, or the greedy
+/// heuristic if asked to (or if linear programming fails). Returns Some if a solution could be
+/// computed, None if there is no profitable trade, or an error if the problem couldn't be set up.
+pub fn solve_knapsack(
+    source: &StationMarket,
+    destination: &StationMarket,
+    params: &SolveParams,
+) -> Result<Option<TradeSolution>, KuralError> {
+    let SolveParams {
+        capacity, capital, ..
+    } = *params;
+    let Some(Candidates {
+        names,
+        items,
+        estimated,
+    }) = candidates(source, destination, params)?
+    else {
+        return Ok(None);
+    };
+
+    let counts = match params.solver {
+        Solver::Highs => solve_highs(&items, capacity, capital).unwrap_or_else(|reason| {
+            warn!(
+                "HiGHS could not solve {} -> {} ({}), falling back to the greedy solver",
+                source.station.name, destination.station.name, reason
+            );
+            solve_greedy(&items, capacity, capital)
+        }),
+        Solver::Greedy => solve_greedy(&items, capacity, capital),
+    };
+
+    let orders: Vec<Order> = names
+        .iter()
+        .zip(counts.iter())
+        .map(|(com, &count)| {
+            let dest_commodity = destination.get_commodity_ref(com);
+            Order::new(
+                com.clone(),
+                count,
+                source
+                    .get_commodity_ref(com)
+                    .map_or(0, |it| it.stock_bracket),
+                dest_commodity.map_or(0, |it| it.demand),
+                dest_commodity.map_or(0, |it| it.demand_bracket),
+                estimated.contains(com),
+            )
+        })
+        .collect();
+
+    // evaluate the rounded counts, not the solver's values, so that these match the orders
+    let profit: f64 = counts
+        .iter()
+        .zip(&items)
+        .map(|(&count, item)| count as f64 * item.unit_profit as f64)
+        .sum();
+    let cost: f64 = counts
+        .iter()
+        .zip(&items)
+        .map(|(&count, item)| count as f64 * item.buy_price as f64)
+        .sum();
+    debug!(
+        "Computed {} -> {} with profit {}",
+        source.station.name, destination.station.name, profit
+    );
+
+    Ok(Some(TradeSolution::new(
+        source.station.clone(),
+        destination.station.clone(),
+        orders,
+        profit,
+        cost,
+        capital,
+    )))
+}
+
+/// Describes the model that `solve_knapsack` builds for the pair of stations: the objective, each
+/// variable with the stock and demand that bound it, and the constraints. The model is then solved,
+/// and the solver's raw values are listed alongside the whole unit counts they're rounded to.
+pub fn explain_knapsack(
+    source: &StationMarket,
+    destination: &StationMarket,
+    params: &SolveParams,
+) -> Result<String, KuralError> {
+    let SolveParams {
+        capacity, capital, ..
+    } = *params;
+    let Some(Candidates {
+        names,
+        items,
+        estimated,
+    }) = candidates(source, destination, params)?
+    else {
+        return Ok("No commodity can be traded at a profit, so no model is built\n".to_string());
+    };
+
+    // writing to a String never fails
+    let mut out = String::new();
+    let sum = |coefficient: fn(&Item) -> String| {
+        (0..items.len())
+            .map(|i| format!("{} x{i}", coefficient(&items[i])))
+            .collect::<Vec<_>>()
+            .join(" + ")
+    };
+    writeln!(out, "Maximise").unwrap();
+    writeln!(out, "    {}", sum(|item| item.unit_profit.to_string())).unwrap();
+    writeln!(out, "Where").unwrap();
+    for (i, (name, item)) in names.iter().zip(&items).enumerate() {
+        let stock = require_commodity(source, name)?.stock;
+        let demand = match destination.get_commodity_ref(name) {
+            Some(sold) if !estimated.contains(name) => sold.demand.to_string(),
+            _ => "unknown, sell price estimated".to_string(),
+        };
+        writeln!(
+            out,
+            "    x{i} = units of {name}, integer in [0, {}] (stock {stock}, demand {demand}), \
+            buys for {} CR, {} CR profit each",
+            item.max, item.buy_price, item.unit_profit
+        )
+        .unwrap();
     }
+    writeln!(out, "Subject to").unwrap();
+    writeln!(
+        out,
+        "    cargo:   {} <= {capacity}",
+        sum(|item| item.weight.to_string())
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    capital: {} <= {capital}",
+        sum(|item| item.buy_price.to_string())
+    )
+    .unwrap();
+
+    let raw = match params.solver {
+        Solver::Highs => Some(
+            solve_highs_values(&items, capacity, capital).map_err(|reason| KuralError::Solver {
+                from: source.station.name.clone(),
+                to: destination.station.name.clone(),
+                reason,
+            })?,
+        ),
+        Solver::Greedy => None,
+    };
+    let counts = match &raw {
+        Some(values) => round_picks(&picks(&items, values), capacity, capital),
+        None => solve_greedy(&items, capacity, capital),
+    };
+    writeln!(out, "Solution ({:?} solver)", params.solver).unwrap();
+    for (i, count) in counts.iter().enumerate() {
+        match &raw {
+            Some(values) => writeln!(out, "    x{i} = {count} (raw {})", values[i]).unwrap(),
+            None => writeln!(out, "    x{i} = {count}").unwrap(),
+        }
+    }
+    Ok(out)
+}
+
+/// Solves the bounded knapsack problem exactly with HiGHS, returning the number of units of each
+/// item to buy
+fn solve_highs(items: &[Item], capacity: u32, capital: u64) -> Result<Vec<u32>, ResolutionError> {
+    // the ILP solver will tell us how many of each commodity to order, although only to within
+    // its tolerance, so round those to whole units that still fit
+    let values = solve_highs_values(items, capacity, capital)?;
+    Ok(round_picks(&picks(items, &values), capacity, capital))
+}
+
+/// Pairs each item with the solver's value for it
+fn picks(items: &[Item], values: &[f64]) -> Vec<Pick> {
+    items
+        .iter()
+        .zip(values)
+        .map(|(item, &value)| Pick {
+            value,
+            buy_price: item.buy_price,
+            unit_profit: item.unit_profit,
+            weight: item.weight,
+        })
+        .collect()
+}
+
+/// Solves the bounded knapsack problem with HiGHS, returning the raw value of each item's variable
+/// before it's rounded to whole units
+fn solve_highs_values(
+    items: &[Item],
+    capacity: u32,
+    capital: u64,
+) -> Result<Vec<f64>, ResolutionError> {
+    let mut vars = ProblemVariables::new();
+    // this represents the number items
+    let x: Vec<Variable> = items
+        .iter()
+        .map(|item| vars.add(variable().min(0).max(item.max).integer()))
+        .collect();
 
     // setup our objective which is sum_(i=1)^n v_i x_i
     // i.e. quantity x profit
     let mut objective = Expression::from(0.0);
-    for (i, prof) in profit.values().enumerate() {
-        objective += x[i] * *prof;
+    for (i, item) in items.iter().enumerate() {
+        objective += x[i] * item.unit_profit;
     }
 
     // setup the quantity and capital constraints
     let mut quantity_expr = Expression::from(0.0);
     let mut capital_expr = Expression::from(0.0);
-    for (i, com) in profit.keys().enumerate() {
-        quantity_expr += x[i];
-        capital_expr += x[i] * source.get_commodity(com).unwrap().buy_price;
+    for (i, item) in items.iter().enumerate() {
+        quantity_expr += x[i] * item.weight;
+        capital_expr += x[i] * item.buy_price;
     }
 
-    let solution = vars
+    let sol = vars
         .maximise(&objective)
         .using(highs)
         .with(constraint!(quantity_expr <= capacity))
-        .with(constraint!(capital_expr.clone() <= (capital as f64)))
-        .solve();
-
-    match solution {
-        Ok(sol) => {
-            let profit = sol.eval(&objective);
-            let cost = sol.eval(capital_expr.clone());
-            debug!(
-                "Computed {} -> {} with profit {}",
-                source.station.name, destination.station.name, profit
-            );
+        .with(constraint!(capital_expr <= (capital as f64)))
+        .solve()?;
+    Ok(x.iter().map(|var| sol.value(*var)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Commodity, Station};
 
-            // the ILP solver will tell us how many of each commodity to order
-            let orders: Vec<Order> = x
-                .iter()
-                .enumerate()
-                .map(|(idx, var)| {
-                    Order::new(
-                        source.commodities[idx].name.clone(),
-                        // FIXME we may be stupid -> .floor() as u32 is kind of dumb
-                        // why is our ILP solve returning float valued constraints anyway?
-                        sol.value(*var).floor() as u32,
-                    )
-                })
-                .collect();
-
-            Some(TradeSolution::new(
-                source.station,
-                destination.station,
-                orders,
-                profit,
-                cost,
-            ))
+    fn station(id: i64) -> Station {
+        Station {
+            id,
+            name: format!("Station {id}"),
+            distance_to_arrival: None,
+            market_id: Some(id),
+            system_id: Some(id),
+            system_name: Some(format!("System {id}")),
+            station_type: None,
         }
-        Err(err) => {
-            error!(
-                "Could not solve {} -> {}: {}",
-                source.station.name, destination.station.name, err
-            );
-            None
+    }
+
+    fn commodity(name: &str, buy_price: i32, sell_price: i32) -> Commodity {
+        Commodity {
+            name: name.to_string(),
+            mean_price: buy_price,
+            buy_price,
+            sell_price,
+            demand: 10_000,
+            demand_bracket: 3,
+            stock: 10_000,
+            stock_bracket: 3,
+            ..Default::default()
+        }
+    }
+
+    /// A commodity that a source station sells at the given price, with the given stock
+    fn selling(name: &str, buy_price: i32, stock: i32) -> Commodity {
+        Commodity {
+            stock,
+            ..commodity(name, buy_price, 0)
+        }
+    }
+
+    /// A commodity that a destination station buys at the given price, with the given demand
+    fn buying(name: &str, sell_price: i32, demand: i32) -> Commodity {
+        Commodity {
+            demand,
+            stock: 0,
+            ..commodity(name, 0, sell_price)
+        }
+    }
+
+    fn params(capacity: u32, capital: u64) -> SolveParams<'static> {
+        SolveParams {
+            capacity,
+            capital,
+            min_unit_profit: 0,
+            min_supply: 0,
+            estimated_sell_prices: None,
+            min_profit: 0,
+            min_roi: None,
+            only_commodities: None,
+            solver: Solver::Highs,
+        }
+    }
+
+    /// Solves a trade between two stations with the given markets, which must find a trade
+    fn solve(
+        source: Vec<Commodity>,
+        destination: Vec<Commodity>,
+        capacity: u32,
+        capital: u64,
+    ) -> TradeSolution {
+        solve_knapsack(
+            &StationMarket::new(station(1), source),
+            &StationMarket::new(station(2), destination),
+            &params(capacity, capital),
+        )
+        .expect("solve should succeed")
+        .expect("a trade should be found")
+    }
+
+    /// Units of each commodity bought, leaving out those that weren't
+    fn bought(trade: &TradeSolution) -> Vec<(&str, u32)> {
+        trade
+            .buy
+            .iter()
+            .filter(|order| order.count > 0)
+            .map(|order| (order.commodity_name.as_str(), order.count))
+            .collect()
+    }
+
+    #[test]
+    fn all_negative_margins_is_none() {
+        let source = StationMarket::new(
+            station(1),
+            vec![commodity("gold", 10_000, 0), commodity("steel", 500, 0)],
+        );
+        let destination = StationMarket::new(
+            station(2),
+            vec![commodity("gold", 0, 9_000), commodity("steel", 0, 500)],
+        );
+
+        assert!(matches!(
+            solve_knapsack(&source, &destination, &params(100, 10_000_000)),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn fills_hold_with_single_profitable_commodity() {
+        let trade = solve(
+            vec![selling("gold", 9_000, 1_000)],
+            vec![buying("gold", 10_000, 1_000)],
+            100,
+            10_000_000,
+        );
+
+        assert_eq!(bought(&trade), vec![("gold", 100)]);
+        assert_eq!(trade.profit, 100_000.0);
+        assert_eq!(trade.cost, 900_000.0);
+    }
+
+    #[test]
+    fn capacity_bound_prefers_highest_unit_profit() {
+        let trade = solve(
+            vec![selling("gold", 9_000, 1_000), selling("steel", 100, 1_000)],
+            vec![buying("gold", 10_000, 1_000), buying("steel", 200, 1_000)],
+            100,
+            10_000_000,
+        );
+
+        assert_eq!(bought(&trade), vec![("gold", 100)]);
+        assert_eq!(trade.profit, 100_000.0);
+        assert_eq!(trade.cost, 900_000.0);
+    }
+
+    #[test]
+    fn capital_bound_buys_only_what_it_can_afford() {
+        let trade = solve(
+            vec![selling("gold", 9_000, 1_000)],
+            vec![buying("gold", 10_000, 1_000)],
+            100,
+            450_000,
+        );
+
+        assert_eq!(bought(&trade), vec![("gold", 50)]);
+        assert_eq!(trade.profit, 50_000.0);
+        assert_eq!(trade.cost, 450_000.0);
+    }
+
+    #[test]
+    fn tops_up_hold_when_demand_is_lower_than_stock() {
+        // gold makes far more per unit, but the destination only wants 30 of it, so the rest of the
+        // hold should be filled with steel rather than left empty
+        let trade = solve(
+            vec![selling("gold", 9_000, 1_000), selling("steel", 100, 1_000)],
+            vec![buying("gold", 10_000, 30), buying("steel", 200, 1_000)],
+            100,
+            10_000_000,
+        );
+
+        assert_eq!(bought(&trade), vec![("gold", 30), ("steel", 70)]);
+        assert_eq!(trade.units(), 100);
+        assert_eq!(trade.profit, 37_000.0);
+        assert_eq!(trade.cost, 277_000.0);
+    }
+
+    #[test]
+    fn no_overlap_is_none() {
+        let source = StationMarket::new(station(1), vec![selling("gold", 9_000, 1_000)]);
+        let destination = StationMarket::new(station(2), vec![buying("steel", 200, 1_000)]);
+
+        assert!(matches!(
+            solve_knapsack(&source, &destination, &params(100, 10_000_000)),
+            Ok(None)
+        ));
+    }
+
+    fn pick(value: f64, buy_price: i32, unit_profit: i32) -> Pick {
+        Pick {
+            value,
+            buy_price,
+            unit_profit,
+            weight: 1,
         }
     }
+
+    #[test]
+    fn rounds_solver_epsilon_to_nearest_unit() {
+        let picks = [pick(99.9999997, 100, 50), pick(20.0000002, 100, 40)];
+
+        assert_eq!(round_picks(&picks, 200, 1_000_000), vec![100, 20]);
+    }
+
+    #[test]
+    fn rounds_fractional_values_down() {
+        let picks = [pick(10.5, 100, 50)];
+
+        assert_eq!(round_picks(&picks, 200, 1_000_000), vec![10]);
+    }
+
+    #[test]
+    fn cuts_least_profitable_pick_when_over_capacity_after_rounding() {
+        let picks = [pick(49.9999999, 100, 50), pick(50.9999999, 100, 10)];
+
+        assert_eq!(round_picks(&picks, 100, 1_000_000), vec![50, 50]);
+    }
+
+    #[test]
+    fn cuts_least_profitable_pick_when_over_budget_after_rounding() {
+        // 50 + 50 units at 1,000 CR each costs 100,000 CR, one unit more than the capital allows
+        let picks = [pick(49.9999999, 1_000, 10), pick(49.9999999, 1_000, 30)];
+
+        assert_eq!(round_picks(&picks, 1_000, 99_999), vec![49, 50]);
+    }
+
+    #[test]
+    fn keeps_picks_with_capital_beyond_i64() {
+        let picks = [pick(50.0, 1_000, 10)];
+
+        assert_eq!(round_picks(&picks, 1_000, u64::MAX), vec![50]);
+    }
+
+    #[test]
+    fn greedy_buys_most_profit_per_credit_first() {
+        let items = [
+            Item {
+                unit_profit: 1_000,
+                buy_price: 10_000,
+                max: 100,
+                weight: 1,
+            },
+            Item {
+                unit_profit: 500,
+                buy_price: 1_000,
+                max: 30,
+                weight: 1,
+            },
+        ];
+
+        // the cheap commodity is bought first, and the rest of the capital goes on the other
+        assert_eq!(solve_greedy(&items, 100, 100_000), vec![7, 30]);
+    }
 }
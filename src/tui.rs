@@ -0,0 +1,223 @@
+use crate::types::TradeSolution;
+use color_eyre::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use thousands::Separable;
+
+/// Column that the trades in the browser are sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortColumn {
+    Profit,
+    Roi,
+    Distance,
+}
+
+/// A trade shown in the browser, alongside its distance, which isn't stored on the trade itself
+#[derive(Debug, Clone)]
+pub struct BrowsedTrade {
+    pub trade: TradeSolution,
+    /// Distance in light years between the source and destination, if both systems are known
+    pub distance: Option<f64>,
+}
+
+/// State of the trade browser
+struct Browser {
+    trades: Vec<BrowsedTrade>,
+    table: TableState,
+    sort: SortColumn,
+    descending: bool,
+}
+
+impl Browser {
+    fn new(trades: Vec<BrowsedTrade>) -> Self {
+        let mut browser = Self {
+            trades,
+            table: TableState::default(),
+            sort: SortColumn::Profit,
+            descending: true,
+        };
+        browser.sort();
+        browser
+    }
+
+    /// Sorts the trades by the current column and direction, and selects the first one
+    fn sort(&mut self) {
+        let sort = self.sort;
+        let key = |trade: &BrowsedTrade| match sort {
+            SortColumn::Profit => trade.trade.profit,
+            SortColumn::Roi => trade.trade.roi(),
+            // trades of an unknown distance always sort last when nearest first
+            SortColumn::Distance => trade.distance.unwrap_or(f64::INFINITY),
+        };
+        self.trades.sort_by(|a, b| key(a).total_cmp(&key(b)));
+        if self.descending {
+            self.trades.reverse();
+        }
+        self.table.select((!self.trades.is_empty()).then_some(0));
+    }
+
+    /// Sorts by the given column, or flips the direction if already sorted by it. Profit and ROI
+    /// start with the best first, and distance with the nearest first.
+    fn sort_by(&mut self, column: SortColumn) {
+        if self.sort == column {
+            self.descending = !self.descending;
+        } else {
+            self.sort = column;
+            self.descending = column != SortColumn::Distance;
+        }
+        self.sort();
+    }
+
+    fn selected(&self) -> Option<&BrowsedTrade> {
+        self.table.selected().and_then(|i| self.trades.get(i))
+    }
+}
+
+/// Opens an interactive, scrollable browser of the given trades, which returns once the user quits
+pub fn browse(trades: Vec<BrowsedTrade>) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, Browser::new(trades));
+    // always give the terminal back, even if drawing failed
+    ratatui::restore();
+    result
+}
+
+fn run(terminal: &mut DefaultTerminal, mut browser: Browser) -> Result<()> {
+    loop {
+        terminal.draw(|frame| draw(frame, &mut browser))?;
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Down | KeyCode::Char('j') => browser.table.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => browser.table.select_previous(),
+            KeyCode::Home | KeyCode::Char('g') => browser.table.select_first(),
+            KeyCode::End | KeyCode::Char('G') => browser.table.select_last(),
+            KeyCode::Char('p') => browser.sort_by(SortColumn::Profit),
+            KeyCode::Char('r') => browser.sort_by(SortColumn::Roi),
+            KeyCode::Char('d') => browser.sort_by(SortColumn::Distance),
+            _ => {}
+        }
+    }
+}
+
+/// Names a station alongside the system it's in
+fn station_label(trade: &TradeSolution, source: bool) -> String {
+    let station = if source {
+        &trade.source
+    } else {
+        &trade.destination
+    };
+    match &station.system_name {
+        Some(system) => format!("{} ({system})", station.name),
+        None => station.name.clone(),
+    }
+}
+
+fn draw(frame: &mut Frame, browser: &mut Browser) {
+    let [table_area, detail_area, help_area] = Layout::vertical([
+        Constraint::Min(5),
+        Constraint::Length(12),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let arrow = if browser.descending { " ▼" } else { " ▲" };
+    let title = |name: &str, column: SortColumn| {
+        if browser.sort == column {
+            format!("{name}{arrow}")
+        } else {
+            name.to_string()
+        }
+    };
+    let header = Row::new([
+        Cell::from("From"),
+        Cell::from("To"),
+        Cell::from(title("Profit (p)", SortColumn::Profit)),
+        Cell::from(title("ROI (r)", SortColumn::Roi)),
+        Cell::from(title("Distance (d)", SortColumn::Distance)),
+    ])
+    .style(Style::new().add_modifier(Modifier::BOLD));
+
+    let rows = browser.trades.iter().map(|browsed| {
+        let trade = &browsed.trade;
+        Row::new([
+            Cell::from(station_label(trade, true)),
+            Cell::from(station_label(trade, false)),
+            Cell::from(format!(
+                "{} CR",
+                trade.profit.round().separate_with_commas()
+            ))
+            .style(Style::new().fg(Color::Green)),
+            Cell::from(format!("{:.0}%", trade.roi() * 100.0)),
+            Cell::from(match browsed.distance {
+                Some(distance) => format!("{distance:.1} LY"),
+                None => "?".to_string(),
+            }),
+        ])
+    });
+
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Percentage(30),
+            Constraint::Percentage(30),
+            Constraint::Percentage(16),
+            Constraint::Percentage(10),
+            Constraint::Percentage(14),
+        ],
+    )
+    .header(header)
+    .block(Block::bordered().title(format!(" {} trades ", browser.trades.len())))
+    .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(table, table_area, &mut browser.table);
+
+    let details: Vec<Line> = match browser.selected() {
+        Some(browsed) => {
+            let trade = &browsed.trade;
+            let mut lines = vec![Line::from(format!(
+                "Buy at {} for {} CR:",
+                station_label(trade, true),
+                trade.cost.round().separate_with_commas()
+            ))];
+            for order in trade.buy.iter().filter(|order| order.count > 0) {
+                lines.push(Line::from(format!(
+                    "    {}x {}{}",
+                    order.count,
+                    order.commodity_name,
+                    if order.estimated {
+                        " (estimated sell price)"
+                    } else {
+                        ""
+                    }
+                )));
+            }
+            lines.push(Line::from(format!(
+                "Sell at {} for {} CR profit",
+                station_label(trade, false),
+                trade.profit.round().separate_with_commas()
+            )));
+            lines
+        }
+        None => vec![Line::from("No trades were found")],
+    };
+    frame.render_widget(
+        Paragraph::new(details).block(Block::bordered().title(" Details ")),
+        detail_area,
+    );
+
+    frame.render_widget(
+        Paragraph::new("↑/↓ or j/k: move   g/G: first/last   p/r/d: sort   q: quit")
+            .style(Style::new().fg(Color::DarkGray)),
+        help_area,
+    );
+}
@@ -1,16 +1,18 @@
+use crate::error::KuralError;
 use chrono::NaiveDate;
 use chrono::NaiveDateTime;
 use chrono::Utc;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use core::fmt;
 use count_digits::CountDigits;
 use distances::vectors::euclidean;
-use geozero::wkb;
 use geozero::wkb::FromWkb;
 use geozero::wkb::WkbDialect;
 use geozero::CoordDimensions;
 use geozero::GeomProcessor;
 use geozero::GeozeroGeometry;
+use log::warn;
 use owo_colors::colors::css::DarkOrange;
 use owo_colors::colors::css::Orange;
 use owo_colors::colors::*;
@@ -18,7 +20,9 @@ use owo_colors::OwoColorize;
 use serde::Deserialize;
 use serde::Serialize;
 use sqlx::{FromRow, Pool, Postgres};
+use std::collections::HashMap;
 use std::io::Read;
+use std::sync::{Arc, OnceLock};
 use thousands::Separable;
 
 // Credit: Nathan Lilienthal - Galos
@@ -91,15 +95,64 @@ impl Coordinate {
     }
 }
 
+impl Coordinate {
+    /// Decodes a coordinate from the EWKB that PostGIS emits (via ST_AsEWKB). Returns an error if
+    /// the blob is malformed.
+    pub fn from_ewkb(blob: &[u8]) -> geozero::error::Result<Self> {
+        let mut rdr = blob;
+        Coordinate::from_wkb(&mut rdr, WkbDialect::Ewkb)
+    }
+}
+
+/// A row from the systems table, with its coordinates still encoded as EWKB
 #[derive(Debug, FromRow)]
+pub struct SystemRow {
+    pub id: i64,
+    pub name: String,
+    pub date: NaiveDateTime,
+    pub coords: Option<Vec<u8>>,
+    pub needs_permit: Option<bool>,
+}
+
+impl SystemRow {
+    /// Decodes the coordinates of this system. Returns None (and logs why) if the coordinates are
+    /// missing or malformed, so that imperfect imports don't take the whole run down with them.
+    pub fn decode(self) -> Option<System> {
+        let Some(blob) = self.coords else {
+            warn!("System {} has no coordinates, skipping it", self.name);
+            return None;
+        };
+
+        match Coordinate::from_ewkb(&blob) {
+            Ok(coords) => Some(System {
+                id: self.id,
+                name: self.name,
+                date: self.date,
+                coords,
+                needs_permit: self.needs_permit,
+            }),
+            Err(err) => {
+                warn!(
+                    "Could not decode coordinates of system {}, skipping it: {}",
+                    self.name, err
+                );
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct System {
     pub id: i64,
     pub name: String,
     pub date: NaiveDateTime,
-    pub coords: wkb::Decode<Coordinate>,
+    pub coords: Coordinate,
+    /// Whether a permit is needed to enter this system, or None if that isn't known
+    pub needs_permit: Option<bool>,
 }
 
-#[derive(Debug, FromRow, Clone)]
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
 pub struct Station {
     pub id: i64,
     pub name: String,
@@ -107,9 +160,54 @@ pub struct Station {
     pub market_id: Option<i64>,
     pub system_id: Option<i64>,
     pub system_name: Option<String>,
+    /// Type of the station as reported by EDDN, e.g. "Coriolis" or "CraterOutpost", if known
+    pub station_type: Option<String>,
 }
 
-#[derive(Debug, FromRow, Clone)]
+/// A row from the stations table joined with its system, whose coordinates are still encoded as
+/// EWKB
+#[derive(Debug, FromRow)]
+pub struct StationRow {
+    pub id: i64,
+    pub name: String,
+    pub distance_to_arrival: Option<f32>,
+    pub market_id: Option<i64>,
+    pub system_id: Option<i64>,
+    pub system_name: Option<String>,
+    pub station_type: Option<String>,
+    pub system_date: NaiveDateTime,
+    pub system_coords: Option<Vec<u8>>,
+    pub system_needs_permit: Option<bool>,
+}
+
+impl StationRow {
+    /// Splits this row into the station and the still encoded row of the system it's in
+    pub fn split(self) -> (Station, Option<SystemRow>) {
+        let system = match (self.system_id, &self.system_name) {
+            (Some(id), Some(name)) => Some(SystemRow {
+                id,
+                name: name.clone(),
+                date: self.system_date,
+                coords: self.system_coords,
+                needs_permit: self.system_needs_permit,
+            }),
+            _ => None,
+        };
+
+        let station = Station {
+            id: self.id,
+            name: self.name,
+            distance_to_arrival: self.distance_to_arrival,
+            market_id: self.market_id,
+            system_id: self.system_id,
+            system_name: self.system_name,
+            station_type: self.station_type,
+        };
+        (station, system)
+    }
+}
+
+#[derive(Debug, Default, FromRow, Clone, Serialize, Deserialize)]
 pub struct Commodity {
     pub market_id: i64,
     pub name: String,
@@ -123,30 +221,70 @@ pub struct Commodity {
     pub listed_at: NaiveDateTime,
 }
 
+/// A listing of a commodity for sale at a station, found by `find_cheapest`
+#[derive(Debug, FromRow, Serialize)]
+pub struct CheapestListing {
+    pub station_name: String,
+    pub system_name: String,
+    pub buy_price: i32,
+    pub stock: i32,
+    pub listed_at: NaiveDateTime,
+}
+
 /// A station with an attached market
 #[derive(Debug, Clone)]
 pub struct StationMarket {
     pub station: Station,
     pub commodities: Vec<Commodity>,
+    /// Commodities by name, for fast lookup
+    commodities_by_name: HashMap<String, Commodity>,
 }
 
-#[derive(Debug, FromRow, Clone)]
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
 /// Order of commodities to buy or sell in a system
 pub struct Order {
     pub commodity_name: String,
     pub count: u32,
+    /// Stock bracket (0-3) for this commodity at the source station
+    pub source_stock_bracket: i32,
+    /// Demand for this commodity at the destination station
+    pub dest_demand: i32,
+    /// Demand bracket (0-3) for this commodity at the destination station
+    pub dest_demand_bracket: i32,
+    /// True if the commodity isn't listed at the destination, and its sell price was estimated
+    /// from the galactic average
+    pub estimated: bool,
 }
 
 impl Order {
-    pub fn new(commodity_name: String, count: u32) -> Self {
+    pub fn new(
+        commodity_name: String,
+        count: u32,
+        source_stock_bracket: i32,
+        dest_demand: i32,
+        dest_demand_bracket: i32,
+        estimated: bool,
+    ) -> Self {
         Self {
             commodity_name,
             count,
+            source_stock_bracket,
+            dest_demand,
+            dest_demand_bracket,
+            estimated,
         }
     }
 }
 
-#[derive(Debug, FromRow, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Profit that would have been made on a trade by hauling only a single baseline commodity
+pub struct Baseline {
+    pub commodity_name: String,
+    /// None if the commodity isn't traded at both ends of the route
+    pub profit: Option<f64>,
+}
+
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
 /// Solution to a knapsack problem
 pub struct TradeSolution {
     /// Source station
@@ -159,6 +297,22 @@ pub struct TradeSolution {
     pub profit: f64,
     /// Cost to execute the trade
     pub cost: f64,
+    /// Fraction between 0.0 and 1.0 of the available capital that is spent on this trade
+    pub capital_utilization: f64,
+    /// Profit of the best trade in the reverse direction (destination back to source), if it was
+    /// computed
+    pub return_profit: Option<f64>,
+    /// Profit of hauling only the baseline commodity on this route, if it was computed
+    pub baseline: Option<Baseline>,
+    /// Trade back from the destination to the source, if this is a round trip
+    pub return_leg: Option<Box<TradeSolution>>,
+    /// Estimated number of jumps from the source to the destination, if a jump range was given
+    pub jumps: Option<u32>,
+    /// Estimated profit per hour of flying this route there and back, if a jump range was given
+    pub profit_per_hour: Option<f64>,
+    /// Factor between 0.0 and 1.0 that the trade's score was scaled by for the staleness of its
+    /// listings, if `--freshness-weight` was given
+    pub freshness: Option<f64>,
 }
 
 impl TradeSolution {
@@ -168,6 +322,7 @@ impl TradeSolution {
         buy: Vec<Order>,
         profit: f64,
         cost: f64,
+        capital: u64,
     ) -> Self {
         Self {
             source,
@@ -175,28 +330,103 @@ impl TradeSolution {
             buy,
             profit,
             cost,
+            capital_utilization: if capital == 0 {
+                0.0
+            } else {
+                cost / capital as f64
+            },
+            return_profit: None,
+            baseline: None,
+            return_leg: None,
+            jumps: None,
+            profit_per_hour: None,
+            freshness: None,
+        }
+    }
+
+    /// Ratio of destination demand to the quantity sold, for the ordered commodity with the least
+    /// headroom. A high ratio means the destination can absorb this cargo many times over without
+    /// its price collapsing. Returns 0 if nothing is bought.
+    pub fn demand_headroom(&self) -> f64 {
+        self.buy
+            .iter()
+            .filter(|order| order.count > 0)
+            .map(|order| order.dest_demand.max(0) as f64 / order.count as f64)
+            .min_by(f64::total_cmp)
+            .unwrap_or(0.0)
+    }
+
+    /// Profit of this trade, plus the profit of its return leg if it's a round trip
+    pub fn total_profit(&self) -> f64 {
+        self.profit + self.return_leg.as_ref().map_or(0.0, |leg| leg.profit)
+    }
+
+    /// Total number of units bought in this trade
+    pub fn units(&self) -> u32 {
+        self.buy.iter().map(|order| order.count).sum()
+    }
+
+    /// Profit per unit of cargo bought, or 0 if nothing is bought
+    pub fn profit_per_unit(&self) -> f64 {
+        match self.units() {
+            0 => 0.0,
+            units => self.profit / units as f64,
         }
     }
 
-    pub async fn dump_coloured(&self, pool: &Pool<Postgres>) -> String {
+    /// Return on investment, as the ratio of profit to cost, or 0 if nothing is spent
+    pub fn roi(&self) -> f64 {
+        if self.cost <= 0.0 {
+            return 0.0;
+        }
+        self.profit / self.cost
+    }
+
+    /// Returns true if any commodity bought in this trade has an estimated sell price
+    pub fn is_estimated(&self) -> bool {
+        self.buy
+            .iter()
+            .any(|order| order.count > 0 && order.estimated)
+    }
+
+    /// Renders the trade for the terminal. `distance` is the distance in light years between the
+    /// source and destination systems, if it's known.
+    pub async fn dump_coloured(&self, pool: &Pool<Postgres>, distance: Option<f64>) -> String {
         let mut str = format!(
-            "➡️ For {} CR profit:\n    Travel to {} in {} and buy (for {} CR):\n",
+            "➡️ For {} CR profit ({} CR/t, {}% ROI, {}% of capital used){}:\n    Travel to {} in {} and buy (for {} CR):\n",
             self.profit
                 .round()
-                .separate_with_commas()
+                .separate_thousands()
                 .fg::<Green>()
                 .bold(),
+            self.profit_per_unit()
+                .round()
+                .separate_thousands()
+                .fg::<Green>(),
+            (self.roi() * 100.0).round().fg::<Green>(),
+            (self.capital_utilization * 100.0).round().fg::<Orange>(),
+            if self.is_estimated() {
+                " (partially estimated)".fg::<DarkOrange>().to_string()
+            } else {
+                "".to_string()
+            },
             self.source.name.fg::<Orange>(),
-            self.source.get_system_name(pool).await.fg::<Orange>(),
+            system_name_or_unknown(&self.source, pool).await.fg::<Orange>(),
             // often we just get like .000006, so ignore it for the buy cost
-            self.cost.round().separate_with_commas().fg::<Red>(),
+            self.cost.round().separate_thousands().fg::<Red>(),
         );
 
         let commodities = self
             .source
             .get_commodities(pool, &NaiveDate::from_ymd_opt(1970, 1, 1).unwrap().into())
             .await
-            .unwrap();
+            .unwrap_or_else(|err| {
+                warn!(
+                    "Could not fetch commodities of {}: {}",
+                    self.source.name, err
+                );
+                Vec::new()
+            });
         let market = StationMarket::new(self.source.clone(), commodities);
 
         for order in &self.buy {
@@ -204,78 +434,235 @@ impl TradeSolution {
                 continue;
             }
 
-            let update = market
-                .get_commodity(&order.commodity_name)
-                .unwrap()
-                .listed_at;
-            let dur = chrono_humanize::HumanTime::from(update - Utc::now().naive_utc());
+            let listing = market.get_commodity_ref(&order.commodity_name);
+            let dur = match listing {
+                Some(commodity) => {
+                    chrono_humanize::HumanTime::from(commodity.listed_at - Utc::now().naive_utc())
+                        .to_string()
+                }
+                None => "at an unknown time".to_string(),
+            };
             let spacing = 32 - order.commodity_name.len() + 4;
 
             let digit_spacing = 4 - order.count.count_digits() + 1;
 
             str += &format!(
-                "        {}x{}{}{}(updated {})\n",
+                "        {}x{}{}{}stock {} demand {} (updated {}){}{}\n",
                 order.count,
                 " ".repeat(digit_spacing),
                 order.commodity_name,
                 " ".repeat(spacing),
-                dur.fg::<DarkOrange>()
+                bracket_dots(order.source_stock_bracket),
+                // the demand of estimated commodities is unknown
+                if order.estimated {
+                    "???".fg::<DarkOrange>().to_string()
+                } else {
+                    bracket_dots(order.dest_demand_bracket)
+                },
+                dur.fg::<DarkOrange>(),
+                listing.map_or(String::new(), galactic_mean_note),
+                if order.estimated {
+                    " (estimated sell price)".fg::<DarkOrange>().to_string()
+                } else {
+                    "".to_string()
+                }
             )
             .to_string();
         }
         str += &format!(
             "    Then, travel to {} in {} and sell.\n",
             self.destination.name.fg::<Orange>(),
-            self.destination.get_system_name(pool).await.fg::<Orange>()
+            system_name_or_unknown(&self.destination, pool)
+                .await
+                .fg::<Orange>()
         );
 
-        let source_system =
-            get_system_by_name(pool, self.source.system_name.as_ref().unwrap().as_str())
-                .await
-                .unwrap();
+        let mut jumps = match self.jumps {
+            Some(jumps) => format!(", ~{} jumps", jumps.fg::<Orange>()),
+            None => "".to_string(),
+        };
+        if let Some(profit_per_hour) = self.profit_per_hour {
+            jumps += &format!(
+                ", ~{} CR/hour",
+                profit_per_hour.round().separate_thousands().fg::<Green>()
+            );
+        }
+        str += &match distance {
+            Some(distance) => format!(
+                "    (Approximately {} LY{})",
+                format!("{distance:.1}").fg::<Orange>(),
+                jumps
+            ),
+            None => format!("    (Unknown distance{jumps})"),
+        };
 
-        let dest_system = get_system_by_name(
-            pool,
-            self.destination.system_name.as_ref().unwrap().as_str(),
-        )
-        .await
-        .unwrap();
-
-        let distance = source_system
-            .coords
-            .geometry
-            .unwrap()
-            .dst(&dest_system.coords.geometry.unwrap());
-        str += &format!(
-            "    (Approximately {} LY)",
-            (distance.round() as u64).fg::<Orange>()
-        );
+        if let Some(baseline) = &self.baseline {
+            str += &match baseline.profit {
+                Some(profit) => format!(
+                    "\n    (hauling only {} would profit {} CR, so this route makes {} CR more)",
+                    baseline.commodity_name,
+                    profit.round().separate_thousands().fg::<Orange>(),
+                    (self.profit - profit)
+                        .round()
+                        .separate_thousands()
+                        .fg::<Green>()
+                ),
+                None => format!(
+                    "\n    ({} is not traded on this route)",
+                    baseline.commodity_name
+                ),
+            };
+        }
+
+        if let Some(freshness) = self.freshness {
+            str += &format!(
+                "\n    (ranked at {} of its profit, for the age of its listings)",
+                format!("{:.0}%", freshness * 100.0).fg::<DarkOrange>()
+            );
+        }
+
+        if let Some(return_profit) = self.return_profit {
+            str += &format!(
+                "\n    (return leg would profit ~{} CR)",
+                return_profit.round().separate_thousands().fg::<Green>()
+            );
+        }
+
+        if let Some(return_leg) = &self.return_leg {
+            // boxed since this is a recursive async call
+            let rendered = Box::pin(return_leg.dump_coloured(pool, distance)).await;
+            str += &format!(
+                "\n    ↩️ Then, on the return leg (round trip total {} CR):",
+                self.total_profit()
+                    .round()
+                    .separate_thousands()
+                    .fg::<Green>()
+                    .bold()
+            );
+            for line in rendered.lines() {
+                str += &format!("\n    {line}");
+            }
+        }
 
         str
     }
 }
 
+#[derive(Debug, Clone, Copy, clap::ValueEnum, PartialEq, Eq)]
+pub enum NumberFormat {
+    /// 1,234,567
+    Commas,
+    /// 1 234 567
+    Spaces,
+    /// 1.234.567
+    Periods,
+    /// 1234567
+    Plain,
+}
+
+/// How the thousands of credit amounts are grouped in printed trades, set once at startup
+static NUMBER_FORMAT: OnceLock<NumberFormat> = OnceLock::new();
+
+/// Sets how the thousands of credit amounts are grouped in printed trades. Only the first call has
+/// any effect.
+pub fn set_number_format(format: NumberFormat) {
+    let _ = NUMBER_FORMAT.set(format);
+}
+
+/// Groups the thousands of a number according to `--number-format`, defaulting to commas
+trait SeparateThousands {
+    fn separate_thousands(&self) -> String;
+}
+
+impl<T: Separable + ToString> SeparateThousands for T {
+    fn separate_thousands(&self) -> String {
+        match NUMBER_FORMAT.get().copied().unwrap_or(NumberFormat::Commas) {
+            NumberFormat::Commas => self.separate_with_commas(),
+            NumberFormat::Spaces => self.separate_with_spaces(),
+            NumberFormat::Periods => self.separate_with_dots(),
+            NumberFormat::Plain => self.to_string(),
+        }
+    }
+}
+
+/// Gets the name of the system the station is in, falling back to "an unknown system" (and logging
+/// why) if it couldn't be looked up
+async fn system_name_or_unknown(station: &Station, pool: &Pool<Postgres>) -> String {
+    station.get_system_name(pool).await.unwrap_or_else(|err| {
+        warn!("Could not look up the system of {}: {}", station.name, err);
+        "an unknown system".to_string()
+    })
+}
+
+/// Describes how the buy price of a commodity compares to its galactic mean price, in green if it's
+/// cheaper than the mean and red if it's dearer
+fn galactic_mean_note(commodity: &Commodity) -> String {
+    if commodity.mean_price <= 0 {
+        return String::new();
+    }
+    let percent =
+        (commodity.buy_price - commodity.mean_price) as f64 * 100.0 / commodity.mean_price as f64;
+    if percent < 0.0 {
+        format!(" ({:.0}% under galactic mean)", -percent)
+            .fg::<Green>()
+            .to_string()
+    } else {
+        format!(" ({percent:.0}% over galactic mean)")
+            .fg::<Red>()
+            .to_string()
+    }
+}
+
+/// Renders a stock or demand bracket (0-3) as dots, where more filled dots mean the price is more
+/// likely to hold
+fn bracket_dots(bracket: i32) -> String {
+    let filled = bracket.clamp(0, 3) as usize;
+    let dots = format!("{}{}", "●".repeat(filled), "○".repeat(3 - filled));
+    match filled {
+        2 | 3 => dots.fg::<Green>().to_string(),
+        1 => dots.fg::<DarkOrange>().to_string(),
+        _ => dots.fg::<Red>().to_string(),
+    }
+}
+
 impl StationMarket {
     pub fn new(station: Station, commodities: Vec<Commodity>) -> Self {
+        let commodities_by_name = commodities
+            .iter()
+            .map(|commodity| (commodity.name.clone(), commodity.clone()))
+            .collect();
         Self {
             station,
             commodities,
+            commodities_by_name,
         }
     }
 
     /// Finds the commodity in the market
     pub fn get_commodity(&self, name: &String) -> Option<Commodity> {
-        // FIXME we should look this up in a hashtable for perf; O(n) -> O(1)
+        self.commodities_by_name.get(name).cloned()
+    }
+
+    /// Finds the commodity in the market, without cloning it
+    pub fn get_commodity_ref(&self, name: &str) -> Option<&Commodity> {
+        self.commodities_by_name.get(name)
+    }
+
+    /// Counts the number of commodities listed in both this market and the other market
+    pub fn count_overlap(&self, other: &StationMarket) -> usize {
         self.commodities
             .iter()
-            .find(|commodity| *commodity.name == *name)
-            .cloned()
+            .filter(|commodity| other.commodities_by_name.contains_key(&commodity.name))
+            .count()
     }
 }
 
 impl Station {
-    pub async fn get_system_name(self: &Station, pool: &Pool<Postgres>) -> String {
-        return sqlx::query!(
+    pub async fn get_system_name(
+        self: &Station,
+        pool: &Pool<Postgres>,
+    ) -> Result<String, KuralError> {
+        Ok(sqlx::query!(
             r#"
                 SELECT name
                 FROM systems
@@ -284,9 +671,8 @@ impl Station {
             self.system_id
         )
         .fetch_one(pool)
-        .await
-        .unwrap()
-        .name;
+        .await?
+        .name)
     }
 
     /// Gets the commodities in this station, assuming it has a market
@@ -294,10 +680,14 @@ impl Station {
         self: &Station,
         pool: &Pool<Postgres>,
         date_cutoff: &NaiveDateTime,
-    ) -> Result<Vec<Commodity>, sqlx::Error> {
+    ) -> Result<Vec<Commodity>, KuralError> {
+        let market_id = self
+            .market_id
+            .ok_or_else(|| KuralError::MissingMarket(self.name.clone()))?;
+
         // fetch commodities, for each commodity, only selecting the most recent
         // one using a common table subexpression
-        return sqlx::query_as!(
+        Ok(sqlx::query_as!(
             Commodity,
             r#"
                 SELECT DISTINCT ON (l.name)
@@ -315,25 +705,121 @@ impl Station {
                 WHERE l.market_id = $1 AND l.listed_at >= $2
                 ORDER BY l.name, l.listed_at DESC;
             "#,
-            self.market_id.unwrap(),
+            market_id,
             date_cutoff,
         )
         .fetch_all(pool)
-        .await;
+        .await?)
+    }
+}
+
+/// Caches systems looked up by name for the duration of a run. Many stations share a system, and
+/// systems don't change mid-run, so repeated lookups can skip the database entirely.
+#[derive(Debug, Default)]
+pub struct SystemCache {
+    systems: HashMap<String, Arc<System>>,
+}
+
+impl SystemCache {
+    /// Gets a system by its name, only querying the database if it hasn't been looked up before
+    pub async fn get_system_by_name(
+        &mut self,
+        pool: &Pool<Postgres>,
+        name: &str,
+    ) -> Result<Arc<System>> {
+        let key = name.to_lowercase();
+        if let Some(system) = self.systems.get(&key) {
+            return Ok(system.clone());
+        }
+
+        let system = Arc::new(get_system_by_name(pool, name).await?);
+        self.systems.insert(key, system.clone());
+        Ok(system)
     }
 }
 
 /// Gets a system by its name
 pub async fn get_system_by_name(pool: &Pool<Postgres>, name: &str) -> Result<System> {
-    return Ok(sqlx::query_as!(
-        System,
+    sqlx::query_as!(
+        SystemRow,
         r#"
-            SELECT id, name, date, coords AS "coords!: wkb::Decode<Coordinate>"
+            SELECT id, name, date, ST_AsEWKB(coords) AS coords, needs_permit
                 FROM systems
             WHERE LOWER(name) = LOWER($1);
         "#,
         name,
     )
     .fetch_one(pool)
-    .await?);
+    .await?
+    .decode()
+    .ok_or_else(|| eyre!("System {name} has no usable coordinates"))
+}
+
+/// Gets every station with the given name, optionally only those in the given system
+pub async fn get_stations_by_name(
+    pool: &Pool<Postgres>,
+    name: &str,
+    system: Option<&str>,
+) -> Result<Vec<Station>> {
+    Ok(sqlx::query_as!(
+        Station,
+        r#"
+            SELECT s.id, s.name, s.distance_to_arrival, s.market_id, s.system_id,
+                   y.name AS "system_name?", s.station_type
+                FROM stations s
+            LEFT JOIN systems y ON y.id = s.system_id
+                WHERE LOWER(s.name) = LOWER($1) AND ($2::text IS NULL OR LOWER(y.name) = LOWER($2));
+        "#,
+        name,
+        system,
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system_row(coords: Option<Vec<u8>>) -> SystemRow {
+        SystemRow {
+            id: 1,
+            name: "Sol".to_string(),
+            date: NaiveDateTime::default(),
+            coords,
+            needs_permit: None,
+        }
+    }
+
+    #[test]
+    fn decodes_point_z_ewkb() {
+        // little endian, PointZ (0x80000001), followed by x, y and z
+        let mut blob = vec![0x01, 0x01, 0x00, 0x00, 0x80];
+        for value in [1.0f64, 2.0, 3.0] {
+            blob.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let system = system_row(Some(blob)).decode().expect("should decode");
+        assert_eq!(
+            system.coords,
+            Coordinate {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0
+            }
+        );
+    }
+
+    #[test]
+    fn skips_malformed_wkb() {
+        // truncated halfway through the x coordinate
+        let blob = vec![0x01, 0x01, 0x00, 0x00, 0x80, 0x00, 0x00, 0xf0];
+        assert!(Coordinate::from_ewkb(&blob).is_err());
+        assert!(system_row(Some(blob)).decode().is_none());
+    }
+
+    #[test]
+    fn skips_missing_coordinates() {
+        assert!(system_row(None).decode().is_none());
+    }
 }